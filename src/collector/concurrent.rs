@@ -0,0 +1,440 @@
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use super::{schedule_steps, Collector, Stepping};
+use crate::error::VmError;
+use crate::heap::{Handle, Heap, Object};
+use crate::stats::GcReason;
+use crate::value::Value;
+
+/// Mark-sweep collector whose marking runs on a background thread.
+///
+/// A cycle starts with a short pause that snapshots the roots and hands
+/// them to a marker thread, after which the mutator carries on allocating
+/// and overwriting fields while the marker traces. The mutator owns the
+/// heap, so the marker traces a mirror of the object graph instead, kept up
+/// to date by allocation and the write barrier and shared behind a lock the
+/// marker only holds for a small batch of objects at a time.
+///
+/// Marking is snapshot-at-the-beginning: everything reachable when the
+/// cycle started survives it. The write barrier logs the value every
+/// overwritten field held, be it a pair's tail, a vector element or a map
+/// entry, so the marker still reaches objects the mutator moves from
+/// unscanned objects into scanned ones, and objects allocated during the
+/// cycle start out marked. The host may hold handles to objects the
+/// snapshot did not reach, so values pushed or stored while a cycle runs
+/// are logged too, and survive it. Once the marker is done, a second pause
+/// drains what was logged since, copies the marks into the heap and sweeps.
+///
+/// A cycle starts when one of the VM's thresholds is reached, and the VM
+/// collects to finish it once the marker is done.
+pub struct Concurrent {
+    batch: usize,
+    graph: Arc<Mutex<Graph>>,
+    marker: Option<JoinHandle<()>>,
+    cycles: usize,
+    allocated_black: usize,
+    logged: usize,
+}
+
+impl Default for Concurrent {
+    fn default() -> Self {
+        Concurrent {
+            batch: 64,
+            graph: Arc::default(),
+            marker: None,
+            cycles: 0,
+            allocated_black: 0,
+            logged: 0,
+        }
+    }
+}
+
+impl Concurrent {
+    pub fn new() -> Self {
+        Concurrent::default()
+    }
+
+    /// Objects the marker traces before letting go of the graph lock.
+    pub fn batch(mut self, objects: usize) -> Self {
+        self.batch = objects.max(1);
+        self
+    }
+
+    pub fn completed_cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Number of objects allocated while a cycle was marking.
+    pub fn allocated_black(&self) -> usize {
+        self.allocated_black
+    }
+
+    /// Number of overwritten fields the barrier has logged.
+    pub fn logged(&self) -> usize {
+        self.logged
+    }
+
+    /// Root-scan pause: snapshots the roots and starts the marker thread.
+    pub(crate) fn start_marking(&mut self, roots: &[Value]) {
+        if self.is_marking() {
+            return;
+        }
+
+        let mut graph = self.graph.lock().unwrap();
+        graph.epoch += 1;
+        for root in roots.iter().filter_map(Value::as_object) {
+            graph.shade(root);
+        }
+        drop(graph);
+
+        let graph = Arc::clone(&self.graph);
+        let batch = self.batch;
+        self.marker = Some(thread::spawn(move || Graph::run_marker(&graph, batch)));
+    }
+
+    /// Remark pause: waits for the marker, finishes whatever the barrier
+    /// logged after it stopped and sweeps.
+    pub(crate) fn finish_marking(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        let Some(marker) = self.marker.take() else {
+            return Ok(());
+        };
+        marker.join().expect("marker thread panicked");
+
+        let mut graph = self.graph.lock().unwrap();
+        while graph.trace(usize::MAX) {}
+
+        let epoch = graph.epoch;
+        for node in &graph.nodes {
+            if let Some(handle) = node.handle.filter(|_| node.marked_in == epoch) {
+                heap.mark(handle)?;
+            }
+        }
+        heap.sweep();
+
+        for node in &mut graph.nodes {
+            if node.handle.is_some_and(|handle| !heap.contains(handle)) {
+                *node = Node::default();
+            }
+        }
+
+        self.cycles += 1;
+        Ok(())
+    }
+}
+
+impl Collector for Concurrent {
+    fn allocate(&mut self, heap: &mut Heap, object: Object) -> Result<Handle, VmError> {
+        let handle = heap.insert(object)?;
+        let node = Node {
+            handle: Some(handle),
+            children: heap.children(handle),
+            marked_in: 0,
+        };
+
+        let mut graph = self.graph.lock().unwrap();
+        let index = handle.index();
+        if graph.nodes.len() <= index {
+            graph.nodes.resize_with(index + 1, Node::default);
+        }
+        graph.nodes[index] = node;
+        if self.marker.is_some() {
+            graph.nodes[index].marked_in = graph.epoch;
+            self.allocated_black += 1;
+        }
+        Ok(handle)
+    }
+
+    fn schedule(
+        &mut self,
+        heap: &mut Heap,
+        roots: &[Value],
+        due: Option<GcReason>,
+        step_budget: usize,
+    ) -> Result<Option<GcReason>, VmError> {
+        schedule_steps(self, heap, roots, due, step_budget)
+    }
+
+    fn scan_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        // A running cycle keeps everything alive that was reachable when it
+        // started, so it is finished first and a fresh one takes over.
+        self.finish_marking(heap)?;
+        self.start_marking(roots);
+        Ok(())
+    }
+
+    fn scan_scheduled(&mut self, _heap: &mut Heap, _roots: &[Value]) -> Result<(), VmError> {
+        // Only the running cycle is finished; it already took its roots.
+        Ok(())
+    }
+
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        self.finish_marking(heap)
+    }
+
+    fn is_idle(&self) -> bool {
+        !self.is_marking()
+    }
+
+    fn write_barrier(&mut self, heap: &mut Heap, obj: Handle, old: Value, new: Value) {
+        let children = heap.children(obj);
+
+        let mut graph = self.graph.lock().unwrap();
+        graph.nodes[obj.index()].children = children;
+        if self.marker.is_some() {
+            graph.log.extend(old.as_object());
+            graph.log.extend(new.as_object());
+            self.logged += 1;
+        }
+    }
+
+    fn root_pushed(&mut self, _heap: &mut Heap, handle: Handle) {
+        if self.marker.is_some() {
+            self.graph.lock().unwrap().log.push(handle);
+        }
+    }
+}
+
+impl Stepping for Concurrent {
+    /// Starts a cycle if none is running. The marker thread does the
+    /// scanning, so no objects are scanned here.
+    fn step(
+        &mut self,
+        _heap: &mut Heap,
+        roots: &[Value],
+        _budget: usize,
+    ) -> Result<usize, VmError> {
+        self.start_marking(roots);
+        Ok(0)
+    }
+
+    /// Whether a marking cycle has started and not been finished yet.
+    fn is_marking(&self) -> bool {
+        self.marker.is_some()
+    }
+
+    fn marking_done(&self) -> bool {
+        self.marker
+            .as_ref()
+            .is_some_and(|marker| marker.is_finished())
+    }
+}
+
+/// The object graph as the marker sees it.
+#[derive(Default)]
+struct Graph {
+    /// Indexed by handle index.
+    nodes: Vec<Node>,
+    epoch: u32,
+    gray: Vec<Handle>,
+    /// Values the mutator overwrote, stored or pushed since the cycle
+    /// started.
+    log: Vec<Handle>,
+}
+
+#[derive(Default)]
+struct Node {
+    handle: Option<Handle>,
+    children: Vec<Handle>,
+    /// Epoch of the last cycle that marked the object.
+    marked_in: u32,
+}
+
+impl Graph {
+    fn run_marker(graph: &Mutex<Graph>, batch: usize) {
+        while graph.lock().unwrap().trace(batch) {
+            // Let the mutator at the lock before taking the next batch.
+            thread::yield_now();
+        }
+    }
+
+    fn shade(&mut self, handle: Handle) {
+        let node = &mut self.nodes[handle.index()];
+        if node.marked_in != self.epoch {
+            node.marked_in = self.epoch;
+            self.gray.push(handle);
+        }
+    }
+
+    /// Scans up to `budget` gray objects, shading logged values once the
+    /// gray ones run out. Returns whether there is anything left to do.
+    fn trace(&mut self, budget: usize) -> bool {
+        for _ in 0..budget {
+            let Some(handle) = self.gray.pop() else {
+                match self.log.pop() {
+                    Some(logged) => {
+                        self.shade(logged);
+                        continue;
+                    }
+                    None => return false,
+                }
+            };
+
+            let children = std::mem::take(&mut self.nodes[handle.index()].children);
+            for &child in &children {
+                self.shade(child);
+            }
+            self.nodes[handle.index()].children = children;
+        }
+        !self.gray.is_empty() || !self.log.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::collector::collector_tests!(super::Concurrent::new());
+
+    use std::thread;
+
+    use super::Concurrent;
+    use crate::collector::{test_vm, Stepping};
+    use crate::heap::{Handle, ObjectType};
+    use crate::value::Value;
+
+    fn concurrent() -> VM<Concurrent> {
+        test_vm(Concurrent::new().batch(16))
+    }
+
+    fn pair(vm: &VM<Concurrent>, obj: Handle) -> (Value, Value) {
+        match &vm.heap().get(obj).unwrap().obj_type {
+            ObjectType::Pair(p) => (p.head, p.tail),
+            _ => panic!("should be a pair"),
+        }
+    }
+
+    fn number(vm: &VM<Concurrent>, value: impl Into<Value>) -> usize {
+        vm.get_str(value).unwrap().parse().unwrap()
+    }
+
+    /// Builds a list of `len` pairs linked through their heads, holding
+    /// strings of the numbers `0..len` in their tails, and leaves it on the
+    /// stack.
+    /// Returns its pairs in the order the marker reaches them.
+    fn list(vm: &mut VM<Concurrent>, len: usize) -> Vec<Handle> {
+        vm.push_str("end").unwrap();
+        let mut nodes: Vec<_> = (0..len)
+            .map(|i| {
+                vm.push_str(&i.to_string()).unwrap();
+                vm.push_pair().unwrap()
+            })
+            .collect();
+        nodes.reverse();
+        nodes
+    }
+
+    #[test]
+    fn mutator_runs_while_the_marker_traces() {
+        let mutator = thread::spawn(|| {
+            let mut vm = concurrent();
+            let len = 20_000;
+
+            let nodes = list(&mut vm, len);
+            vm.start_marking();
+            assert!(vm.collector().is_marking());
+
+            // Move the values of the back half of the list into the front
+            // half, which the marker scans first, working towards the head.
+            // Once the marker has got past the pair a value moves into, the
+            // value leaves a pair the marker has not scanned yet for one it
+            // already has.
+            for k in (0..len / 2).rev() {
+                let (front, back) = (nodes[k], nodes[len - 1 - k]);
+                let moved = pair(&vm, back).1;
+                vm.set_pair_tail(front, moved).unwrap();
+                let fresh = vm.push_str(&len.to_string()).unwrap();
+                vm.set_pair_tail(back, fresh).unwrap();
+                vm.pop().unwrap();
+            }
+
+            vm.finish_marking().unwrap();
+            (vm, nodes)
+        });
+        let (vm, nodes) = mutator.join().unwrap();
+
+        assert_eq!(vm.collector().completed_cycles(), 1);
+        let len = nodes.len();
+        assert_eq!(vm.collector().logged(), len);
+        assert_eq!(vm.collector().allocated_black(), len / 2);
+
+        for (k, &node) in nodes.iter().enumerate() {
+            let expected = if k < len / 2 { k } else { len };
+            assert_eq!(number(&vm, pair(&vm, node).1), expected);
+        }
+    }
+
+    #[test]
+    fn unreachable_objects_are_swept_at_remark() {
+        let mut vm = concurrent();
+
+        let nodes = list(&mut vm, 100);
+        vm.start_marking();
+
+        // Both were reachable when the cycle started, or allocated during
+        // it, so they only go in the next one.
+        let garbage = vm.push_str("7").unwrap();
+        vm.pop().unwrap();
+        let overwritten = pair(&vm, nodes[0]).1.as_object().unwrap();
+        vm.set_pair_tail(nodes[0], nodes[0]).unwrap();
+        vm.finish_marking().unwrap();
+
+        assert!(vm.heap().contains(garbage));
+        assert!(vm.heap().contains(overwritten));
+        assert_eq!(vm.heap().len(), 2 * 100 + 2);
+
+        vm.gc().unwrap();
+        assert!(!vm.heap().contains(garbage));
+        assert!(!vm.heap().contains(overwritten));
+        assert_eq!(vm.heap().len(), 2 * 100);
+    }
+
+    #[test]
+    fn objects_outside_the_snapshot_survive_being_reused() {
+        let mut vm = concurrent();
+
+        let nodes = list(&mut vm, 10);
+        let pushed = vm.push_str("pushed").unwrap();
+        let stored = vm.push_str("stored").unwrap();
+        vm.pop().unwrap();
+        vm.pop().unwrap();
+        vm.start_marking();
+
+        // Neither was reachable when the cycle started, but the host still
+        // holds their handles.
+        vm.push(pushed).unwrap();
+        vm.set_pair_tail(nodes[0], stored).unwrap();
+        vm.finish_marking().unwrap();
+
+        assert_eq!(vm.verify_heap(), vec![]);
+        assert_eq!(vm.get_str(pushed), Ok("pushed"));
+        assert_eq!(vm.get_str(stored), Ok("stored"));
+    }
+
+    #[test]
+    fn allocation_drives_concurrent_cycles() {
+        let mutator = thread::spawn(|| {
+            let mut vm = VM::with_collector(
+                GcConfig::new().stack_size(64).initial_threshold(64),
+                Concurrent::new().batch(16),
+            );
+            let mut kept = Vec::new();
+
+            for i in 0..20_000 {
+                let obj = vm.push_str(&i.to_string()).unwrap();
+                if i % 1000 == 0 && kept.len() < 32 {
+                    kept.push((obj, i));
+                } else {
+                    vm.pop().unwrap();
+                }
+            }
+            vm.gc().unwrap();
+            (vm, kept)
+        });
+        let (vm, kept) = mutator.join().unwrap();
+
+        assert!(vm.collector().completed_cycles() > 1);
+        assert_eq!(vm.heap().len(), kept.len());
+        for (obj, i) in kept {
+            assert_eq!(number(&vm, obj), i);
+        }
+    }
+}
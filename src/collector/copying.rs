@@ -0,0 +1,145 @@
+use super::Collector;
+use crate::error::VmError;
+use crate::heap::{Cell, Heap};
+use crate::value::Value;
+
+/// Cheney's semi-space copying collector.
+///
+/// Everything reachable from the roots is evacuated breadth-first into an
+/// empty to-space, using the to-space itself as the scan queue. Whatever is
+/// left in from-space afterwards is garbage and is released in one go; the
+/// emptied from-space becomes the next collection's to-space.
+///
+/// Pair fields hold handles, which resolve through the heap's handle table,
+/// so fixing up the table after the flip updates every reference at once.
+/// Survivors end up contiguous, and because nothing is ever freed in the
+/// middle of the space, allocation is a bump at the end of the heap.
+#[derive(Default)]
+pub struct SemiSpace {
+    from_space: Option<Vec<Cell>>,
+    spare: Vec<Cell>,
+}
+
+impl SemiSpace {
+    pub fn new() -> Self {
+        SemiSpace::default()
+    }
+}
+
+impl Collector for SemiSpace {
+    fn scan_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        let mut from_space = heap.begin_evacuation(std::mem::take(&mut self.spare));
+
+        for root in roots.iter().filter_map(Value::as_object) {
+            heap.evacuate(&mut from_space, root)?;
+        }
+
+        self.from_space = Some(from_space);
+        Ok(())
+    }
+
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        let mut from_space = self
+            .from_space
+            .take()
+            .expect("scan_roots should run before collect");
+
+        let mut scan = 0;
+        let mut children = Vec::new();
+        while scan < heap.extent() {
+            heap.object_at(scan)
+                .expect("to-space should be densely packed")
+                .trace(|child| children.push(child));
+
+            for child in children.drain(..) {
+                heap.evacuate(&mut from_space, child)?;
+            }
+            scan += 1;
+        }
+
+        self.spare = heap.finish_evacuation(from_space);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::collector::collector_tests!(super::SemiSpace::new());
+
+    use crate::heap::ObjectType;
+
+    #[test]
+    fn survivors_are_compacted() {
+        let mut vm = vm(10);
+
+        for i in 0..6 {
+            vm.push_str(&i.to_string()).unwrap();
+            if i % 2 == 1 {
+                vm.pop().unwrap();
+            }
+        }
+
+        vm.gc().unwrap();
+
+        assert_eq!(vm.heap().len(), 3);
+        assert_eq!(vm.heap().extent(), 3);
+    }
+
+    #[test]
+    fn allocation_bumps_past_survivors() {
+        let mut vm = vm(10);
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+
+        let obj = vm.push_str("3").unwrap();
+
+        assert_eq!(vm.heap().address(obj), Some(1));
+    }
+
+    #[test]
+    fn pairs_follow_their_moved_fields() {
+        let mut vm = vm(10);
+
+        vm.push_str("0").unwrap();
+        vm.pop().unwrap();
+        let one = vm.push_str("1").unwrap();
+        let two = vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+        vm.push_str("3").unwrap();
+        vm.push_pair().unwrap();
+
+        let before = vm.heap().address(one).unwrap();
+        vm.gc().unwrap();
+
+        assert_ne!(vm.heap().address(one), Some(before));
+        match &vm.heap().get(pair).unwrap().obj_type {
+            ObjectType::Pair(p) => {
+                assert_eq!(vm.get_str(p.head), Ok("1"));
+                assert_eq!(vm.get_str(p.tail), Ok("2"));
+                assert_eq!(p.tail, two.into());
+            }
+            _ => panic!("should be a pair"),
+        }
+    }
+
+    #[test]
+    fn shared_objects_are_copied_once() {
+        let mut vm = vm(10);
+
+        let shared = vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        vm.push_pair().unwrap();
+        vm.push_str("3").unwrap();
+        let b = vm.push_pair().unwrap();
+        vm.set_pair_tail(b, shared).unwrap();
+
+        vm.gc().unwrap();
+
+        assert_eq!(vm.heap().len(), 4);
+        assert_eq!(vm.heap().extent(), 4);
+        assert!(vm.heap().contains(shared));
+    }
+}
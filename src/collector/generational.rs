@@ -0,0 +1,361 @@
+use std::collections::HashSet;
+
+use super::mark_stack::MarkStack;
+use super::Collector;
+use crate::error::VmError;
+use crate::heap::{Handle, Heap, Object};
+use crate::stats::GcReason;
+use crate::value::Value;
+
+const OLD: u8 = u8::MAX;
+
+/// Two-generation collector with sticky mark bits.
+///
+/// New objects are linked at the head of the heap's object list and every
+/// object surviving a minor collection ages by one, so the list always holds
+/// the nursery as a prefix followed by the old generation, with ages never
+/// increasing towards the head. A minor collection therefore only has to
+/// walk that prefix, and promotion just moves the boundary. Pointers from
+/// old objects into the nursery are recorded by the write barrier and
+/// treated as extra roots.
+///
+/// The VM's regular [`gc`](crate::VM::gc) performs a major collection, which
+/// collects both generations and tenures every survivor. The VM's object
+/// threshold only counts the old generation, so filling the nursery never
+/// brings a major collection forward.
+pub struct Generational {
+    nursery_size: usize,
+    promotion_age: u8,
+    ages: Vec<u8>,
+    nursery_len: usize,
+    first_old: Option<Handle>,
+    remembered: HashSet<Handle>,
+    mark_stack: MarkStack,
+    minor_collections: usize,
+}
+
+impl Default for Generational {
+    fn default() -> Self {
+        Generational {
+            nursery_size: 64,
+            promotion_age: 2,
+            ages: Vec::new(),
+            nursery_len: 0,
+            first_old: None,
+            remembered: HashSet::new(),
+            mark_stack: MarkStack::default(),
+            minor_collections: 0,
+        }
+    }
+}
+
+impl Generational {
+    pub fn new() -> Self {
+        Generational::default()
+    }
+
+    /// Number of young objects that triggers a minor collection.
+    pub fn nursery_size(mut self, size: usize) -> Self {
+        self.nursery_size = size;
+        self
+    }
+
+    /// Number of minor collections an object has to survive to be promoted.
+    pub fn promotion_age(mut self, age: u8) -> Self {
+        self.promotion_age = age.clamp(1, OLD - 1);
+        self
+    }
+
+    pub fn is_young(&self, handle: Handle) -> bool {
+        self.ages.get(handle.index()).is_some_and(|&age| age != OLD)
+    }
+
+    pub fn nursery_len(&self) -> usize {
+        self.nursery_len
+    }
+
+    pub fn remembered_set(&self) -> &HashSet<Handle> {
+        &self.remembered
+    }
+
+    pub fn minor_collections(&self) -> usize {
+        self.minor_collections
+    }
+
+    /// Marks the young objects reachable from the roots and from the
+    /// remembered set.
+    fn mark_nursery(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        let mut worklist: Vec<Handle> = roots
+            .iter()
+            .filter_map(Value::as_object)
+            .filter(|&root| self.is_young(root))
+            .collect();
+
+        for &obj in &self.remembered {
+            heap.get(obj)
+                .ok_or(VmError::InvalidHandle)?
+                .trace(|child| worklist.push(child));
+        }
+
+        while let Some(handle) = worklist.pop() {
+            if !self.is_young(handle) {
+                continue;
+            }
+
+            if !heap.mark(handle)? {
+                continue;
+            }
+            heap.get(handle)
+                .unwrap()
+                .trace(|child| worklist.push(child));
+        }
+        Ok(())
+    }
+
+    /// Frees the unmarked young objects and ages the rest, promoting those
+    /// old enough.
+    fn sweep_nursery(&mut self, heap: &mut Heap) {
+        let mut promoted = Vec::new();
+        let promotion_age = self.promotion_age;
+        let ages = &mut self.ages;
+        // Old objects are never marked by a minor collection, so survivors
+        // are unmarked one by one rather than by flipping the mark epoch.
+        heap.sweep_until(self.first_old, |handle| {
+            let age = &mut ages[handle.index()];
+            *age += 1;
+            if *age >= promotion_age {
+                *age = OLD;
+                promoted.push(handle);
+            }
+        });
+
+        if let Some(&first) = promoted.first() {
+            self.first_old = Some(first);
+        }
+
+        // Promoted objects may still point at younger ones, and remembered
+        // objects whose young targets were all promoted can be forgotten.
+        let mut remembered = std::mem::take(&mut self.remembered);
+        remembered.extend(promoted);
+        remembered.retain(|&obj| self.points_into_nursery(heap, obj));
+        self.remembered = remembered;
+
+        self.nursery_len = heap.iter().take_while(|&(h, _)| self.is_young(h)).count();
+        self.minor_collections += 1;
+    }
+
+    fn points_into_nursery(&self, heap: &Heap, obj: Handle) -> bool {
+        let mut young = false;
+        if let Some(obj) = heap.get(obj) {
+            obj.trace(|child| young |= self.is_young(child));
+        }
+        young
+    }
+}
+
+impl Collector for Generational {
+    fn allocate(&mut self, heap: &mut Heap, object: Object) -> Result<Handle, VmError> {
+        let handle = heap.insert(object)?;
+        if self.ages.len() <= handle.index() {
+            self.ages.resize(handle.index() + 1, OLD);
+        }
+
+        // Large objects are not on the object list, so they cannot be part
+        // of its nursery prefix. They are tenured right away instead.
+        if heap.in_large_space(handle) {
+            self.ages[handle.index()] = OLD;
+        } else {
+            self.ages[handle.index()] = 0;
+            self.nursery_len += 1;
+        }
+        Ok(handle)
+    }
+
+    fn schedule(
+        &mut self,
+        _heap: &mut Heap,
+        _roots: &[Value],
+        due: Option<GcReason>,
+        _step_budget: usize,
+    ) -> Result<Option<GcReason>, VmError> {
+        // A major collection takes the nursery along.
+        let minor = self.nursery_len >= self.nursery_size;
+        Ok(due.or(minor.then_some(GcReason::Minor)))
+    }
+
+    fn scan_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        for root in roots.iter().filter_map(Value::as_object) {
+            self.mark_stack.mark(heap, root)?;
+        }
+        self.mark_stack.drain(heap)
+    }
+
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        heap.sweep();
+        for (handle, _) in heap.iter() {
+            self.ages[handle.index()] = OLD;
+        }
+
+        self.first_old = heap.first_object();
+        self.nursery_len = 0;
+        self.remembered.clear();
+        Ok(())
+    }
+
+    fn scan_scheduled(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        self.mark_nursery(heap, roots)
+    }
+
+    fn collect_scheduled(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        self.sweep_nursery(heap);
+        Ok(())
+    }
+
+    fn threshold_objects(&self, heap: &Heap) -> usize {
+        // The nursery has its own limit, so the threshold only paces major
+        // collections.
+        heap.len() - self.nursery_len
+    }
+
+    fn write_barrier(&mut self, _heap: &mut Heap, obj: Handle, _old: Value, new: Value) {
+        if !self.is_young(obj) && new.as_object().is_some_and(|new| self.is_young(new)) {
+            self.remembered.insert(obj);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::collector::collector_tests!(super::Generational::new());
+
+    use super::Generational;
+    use crate::collector::test_vm;
+
+    fn generational(nursery_size: usize, promotion_age: u8) -> VM<Generational> {
+        test_vm(
+            Generational::new()
+                .nursery_size(nursery_size)
+                .promotion_age(promotion_age),
+        )
+    }
+
+    #[test]
+    fn minor_gc_only_collects_the_nursery() {
+        let mut vm = generational(100, 1);
+
+        vm.push_str("1").unwrap();
+        vm.minor_gc().unwrap();
+        vm.pop().unwrap();
+
+        vm.push_str("2").unwrap();
+        vm.pop().unwrap();
+        vm.minor_gc().unwrap();
+
+        assert_eq!(vm.heap().len(), 1);
+
+        vm.major_gc().unwrap();
+
+        assert_eq!(vm.heap().len(), 0);
+    }
+
+    #[test]
+    fn survivors_are_promoted_after_promotion_age() {
+        let mut vm = generational(100, 2);
+
+        let obj = vm.push_str("1").unwrap();
+
+        vm.minor_gc().unwrap();
+        assert!(vm.collector().is_young(obj));
+
+        vm.minor_gc().unwrap();
+        assert!(!vm.collector().is_young(obj));
+        assert_eq!(vm.collector().nursery_len(), 0);
+    }
+
+    #[test]
+    fn write_barrier_keeps_young_objects_referenced_from_old_ones() {
+        let mut vm = generational(100, 1);
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+        vm.minor_gc().unwrap();
+        assert!(!vm.collector().is_young(pair));
+
+        let young = vm.push_str("3").unwrap();
+        vm.set_pair_tail(pair, young).unwrap();
+        vm.pop().unwrap();
+
+        assert!(vm.collector().remembered_set().contains(&pair));
+
+        vm.minor_gc().unwrap();
+
+        assert!(vm.heap().contains(young));
+        assert!(!vm.collector().remembered_set().contains(&pair));
+    }
+
+    #[test]
+    fn promoted_objects_pointing_at_young_ones_are_remembered() {
+        let mut vm = generational(100, 2);
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+        vm.minor_gc().unwrap();
+
+        let young = vm.push_str("3").unwrap();
+        vm.set_pair_tail(pair, young).unwrap();
+        vm.pop().unwrap();
+
+        vm.minor_gc().unwrap();
+        assert!(!vm.collector().is_young(pair));
+        assert!(vm.collector().is_young(young));
+        assert!(vm.collector().remembered_set().contains(&pair));
+
+        vm.minor_gc().unwrap();
+        assert!(vm.heap().contains(young));
+    }
+
+    #[test]
+    fn large_objects_are_tenured_at_once() {
+        let mut vm = VM::with_collector(GcConfig::new().large_object_size(16), Generational::new());
+
+        let large = vm.push_bytes(&[0; 16]).unwrap();
+        vm.pop().unwrap();
+        assert!(!vm.collector().is_young(large));
+        assert_eq!(vm.collector().nursery_len(), 0);
+
+        vm.minor_gc().unwrap();
+        assert!(vm.heap().contains(large));
+
+        vm.major_gc().unwrap();
+        assert!(!vm.heap().contains(large));
+    }
+
+    #[test]
+    fn allocation_triggers_minor_collections() {
+        let mut vm = generational(4, 2);
+
+        for i in 0..20 {
+            vm.push_str(&i.to_string()).unwrap();
+            vm.pop().unwrap();
+        }
+
+        assert_eq!(vm.collector().minor_collections(), 4);
+        assert!(vm.heap().len() <= 4);
+    }
+
+    #[test]
+    fn short_lived_objects_die_in_minor_collections() {
+        let mut vm = VM::with_collector(GcConfig::new(), Generational::new());
+
+        for i in 0..10_000 {
+            vm.push_str(&i.to_string()).unwrap();
+            vm.pop().unwrap();
+        }
+
+        assert!(vm.collector().minor_collections() > 100);
+        assert!(vm.heap().len() <= 64);
+        assert_eq!(vm.stats().collections, vm.collector().minor_collections());
+    }
+}
@@ -0,0 +1,411 @@
+use super::mark_stack::MarkStack;
+use super::Collector;
+use crate::error::VmError;
+use crate::heap::{Handle, Heap, Object};
+use crate::value::Value;
+
+/// Bytes per heap cell. Objects start on a cell boundary, so an object's
+/// address times this is its offset in bytes.
+const GRANULE: usize = 16;
+const LINE_SIZE: usize = 128;
+const BLOCK_SIZE: usize = 32 * 1024;
+
+const LINES_PER_BLOCK: usize = BLOCK_SIZE / LINE_SIZE;
+
+/// Immix-style mark-region collector.
+///
+/// The heap is carved into blocks of lines, and objects take as many bytes
+/// as they are big. Marking an object also marks every line it covers.
+/// Allocation bumps through holes, runs of lines the last collection found
+/// empty, and only takes a fresh block at the end of the heap once no hole
+/// fits the object, so survivors stay where they are. Blocks the last
+/// collection left nearly empty are evacuated by the next one instead: their
+/// objects move into holes of other blocks as they are marked, as long as
+/// there is room, which leaves the whole block free for allocation.
+pub struct Immix {
+    mark_stack: MarkStack,
+    evacuation_threshold: usize,
+    /// Whether each line held a live object after the last collection.
+    live_lines: Vec<bool>,
+    bump: Bump,
+    marking: Marking,
+    evacuated: usize,
+}
+
+impl Default for Immix {
+    fn default() -> Self {
+        Immix {
+            mark_stack: MarkStack::default(),
+            evacuation_threshold: LINES_PER_BLOCK / 4,
+            live_lines: Vec::new(),
+            bump: Bump::default(),
+            marking: Marking::default(),
+            evacuated: 0,
+        }
+    }
+}
+
+impl Immix {
+    pub fn new() -> Self {
+        Immix::default()
+    }
+
+    /// Evacuates blocks left with at most this many live lines. Zero turns
+    /// evacuation off.
+    pub fn evacuation_threshold(mut self, lines: usize) -> Self {
+        self.evacuation_threshold = lines;
+        self
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.live_lines.len() / LINES_PER_BLOCK
+    }
+
+    /// Number of lines the last collection found a live object in.
+    pub fn live_lines(&self) -> usize {
+        self.live_lines.iter().filter(|&&live| live).count()
+    }
+
+    /// Number of objects the last collection moved out of sparse blocks.
+    pub fn evacuated(&self) -> usize {
+        self.evacuated
+    }
+
+    fn start_marking(&mut self) {
+        let candidates: Vec<bool> = self
+            .live_lines
+            .chunks(LINES_PER_BLOCK)
+            .map(|block| {
+                let live = block.iter().filter(|&&live| live).count();
+                live > 0 && live <= self.evacuation_threshold
+            })
+            .collect();
+
+        // Blocks being evacuated are no place to move objects into.
+        let mut targets = self.live_lines.clone();
+        for (block, lines) in targets.chunks_mut(LINES_PER_BLOCK).enumerate() {
+            if candidates[block] {
+                lines.fill(true);
+            }
+        }
+
+        self.marking = Marking {
+            lines: vec![false; self.live_lines.len()],
+            candidates,
+            targets,
+            // Objects allocated since the last collection fill the holes
+            // behind the allocator, so survivors move past it.
+            bump: Bump::at(self.bump.cursor),
+            evacuated: 0,
+        };
+    }
+}
+
+/// Bytes `object` takes in the layout.
+fn footprint(object: &Object) -> usize {
+    object.size().next_multiple_of(GRANULE)
+}
+
+/// The collection in progress.
+#[derive(Default)]
+struct Marking {
+    /// Whether each line holds an object marked so far.
+    lines: Vec<bool>,
+    /// Whether each block is being evacuated.
+    candidates: Vec<bool>,
+    /// Lines objects moved out of evacuated blocks cannot go to.
+    targets: Vec<bool>,
+    bump: Bump,
+    evacuated: usize,
+}
+
+impl Marking {
+    /// Called with every object as it is marked, before its fields are
+    /// scanned.
+    fn visit(&mut self, heap: &mut Heap, handle: Handle) -> Result<(), VmError> {
+        if heap.in_large_space(handle) {
+            return Ok(());
+        }
+
+        let mut offset = heap.address(handle).ok_or(VmError::InvalidHandle)? * GRANULE;
+        let size = footprint(heap.get(handle).ok_or(VmError::InvalidHandle)?);
+
+        if self.candidates[offset / BLOCK_SIZE] {
+            if let Some(new_offset) = self.bump.alloc(&self.targets, size) {
+                heap.relocate(offset / GRANULE, new_offset / GRANULE);
+                offset = new_offset;
+                self.evacuated += 1;
+            }
+        }
+
+        // A vector or map that grew since it was placed may reach past the
+        // end of the heap.
+        let last = ((offset + size - 1) / LINE_SIZE).min(self.lines.len() - 1);
+        self.lines[offset / LINE_SIZE..=last].fill(true);
+        Ok(())
+    }
+}
+
+impl Collector for Immix {
+    fn allocate(&mut self, heap: &mut Heap, object: Object) -> Result<Handle, VmError> {
+        // Large objects never take up lines.
+        if heap.is_large(&object) {
+            return heap.insert(object);
+        }
+
+        let size = footprint(&object);
+        let offset = loop {
+            if let Some(offset) = self.bump.alloc(&self.live_lines, size) {
+                break offset;
+            }
+            let blocks = size.div_ceil(BLOCK_SIZE);
+            self.live_lines
+                .resize(self.live_lines.len() + blocks * LINES_PER_BLOCK, false);
+        };
+        heap.insert_at(offset / GRANULE, object)
+    }
+
+    fn scan_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        self.start_marking();
+
+        let Immix {
+            mark_stack,
+            marking,
+            ..
+        } = self;
+        let mut visit = |heap: &mut Heap, handle| marking.visit(heap, handle);
+        for root in roots.iter().filter_map(Value::as_object) {
+            mark_stack.mark_visiting(heap, root, &mut visit)?;
+        }
+        mark_stack.drain_visiting(heap, &mut visit)
+    }
+
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        heap.sweep();
+        heap.forget_free_cells();
+
+        let marking = std::mem::take(&mut self.marking);
+        self.live_lines = marking.lines;
+        self.evacuated = marking.evacuated;
+        self.bump = Bump::default();
+        Ok(())
+    }
+}
+
+/// Bump allocation through the holes of a line map, in address order.
+/// `cursor` and `limit` are the byte offsets bounding the free part of the
+/// current hole.
+#[derive(Default)]
+struct Bump {
+    cursor: usize,
+    limit: usize,
+}
+
+impl Bump {
+    /// Starts at the first line boundary at or after `offset`.
+    fn at(offset: usize) -> Self {
+        let offset = offset.next_multiple_of(LINE_SIZE);
+        Bump {
+            cursor: offset,
+            limit: offset,
+        }
+    }
+
+    /// Claims `size` bytes from the current hole, or from the next hole big
+    /// enough if they do not fit, and returns their offset. `None` if no
+    /// hole further on fits them.
+    fn alloc(&mut self, lines: &[bool], size: usize) -> Option<usize> {
+        if self.limit - self.cursor < size {
+            let mut start = self.limit.div_ceil(LINE_SIZE);
+            let end = loop {
+                start += lines.get(start..)?.iter().position(|&live| !live)?;
+                let mut end = lines[start..]
+                    .iter()
+                    .position(|&live| live)
+                    .map_or(lines.len(), |len| start + len);
+                // Holes end with their block, except that objects bigger
+                // than a block take whole free blocks in a row.
+                if size <= BLOCK_SIZE || !start.is_multiple_of(LINES_PER_BLOCK) {
+                    end = end.min((start / LINES_PER_BLOCK + 1) * LINES_PER_BLOCK);
+                }
+                if (end - start) * LINE_SIZE >= size {
+                    break end;
+                }
+                start = end;
+            };
+
+            self.cursor = start * LINE_SIZE;
+            self.limit = end * LINE_SIZE;
+        }
+
+        self.cursor += size;
+        Some(self.cursor - size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::collector::collector_tests!(super::Immix::new());
+
+    use super::{footprint, Immix, BLOCK_SIZE, GRANULE, LINES_PER_BLOCK, LINE_SIZE};
+    use crate::collector::test_vm;
+    use crate::heap::Handle;
+    use crate::value::Value;
+
+    fn offset(vm: &VM<Immix>, obj: Handle) -> usize {
+        vm.heap().address(obj).unwrap() * GRANULE
+    }
+
+    fn end(vm: &VM<Immix>, obj: Handle) -> usize {
+        offset(vm, obj) + footprint(vm.heap().get(obj).unwrap())
+    }
+
+    /// Allocates garbage until the next object would start at or past
+    /// `offset`.
+    fn fill_to(vm: &mut VM<Immix>, offset: usize) {
+        loop {
+            let obj = vm.push_str("0").unwrap();
+            vm.pop().unwrap();
+            if end(vm, obj) >= offset {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn objects_take_their_size() {
+        let mut vm = test_vm(Immix::new());
+
+        let a = vm.push_str("1").unwrap();
+        let b = vm.push_str("2").unwrap();
+        vm.push_pair().unwrap();
+
+        assert_eq!(offset(&vm, a), 0);
+        assert_eq!(offset(&vm, b), end(&vm, a));
+    }
+
+    #[test]
+    fn allocation_bumps_through_holes() {
+        let mut vm = test_vm(Immix::new());
+
+        fill_to(&mut vm, LINE_SIZE);
+        let kept = vm.push_str("1").unwrap();
+        fill_to(&mut vm, 3 * LINE_SIZE);
+
+        vm.gc().unwrap();
+        let first_live = offset(&vm, kept) / LINE_SIZE;
+        let last_live = (end(&vm, kept) - 1) / LINE_SIZE;
+        assert_eq!(vm.collector().live_lines(), last_live - first_live + 1);
+
+        let first = vm.push_str("2").unwrap();
+        assert_eq!(offset(&vm, first), 0);
+
+        let mut obj = first;
+        while offset(&vm, obj) < first_live * LINE_SIZE {
+            obj = vm.push_str("3").unwrap();
+        }
+        assert_eq!(offset(&vm, obj), (last_live + 1) * LINE_SIZE);
+    }
+
+    #[test]
+    fn objects_mark_every_line_they_cover() {
+        let mut vm = test_vm(Immix::new());
+
+        let straddling = vm.push_str(&"1".repeat(LINE_SIZE)).unwrap();
+        assert_eq!(offset(&vm, straddling), 0);
+        assert!((LINE_SIZE + 1..=2 * LINE_SIZE).contains(&end(&vm, straddling)));
+
+        vm.gc().unwrap();
+
+        assert_eq!(vm.collector().live_lines(), 2);
+    }
+
+    #[test]
+    fn large_objects_take_no_lines() {
+        let mut vm = VM::with_collector(GcConfig::new().large_object_size(16), Immix::new());
+
+        let large = vm.push_bytes(&[7; 16]).unwrap();
+        let small = vm.push_str("small").unwrap();
+        assert_eq!(offset(&vm, small), 0);
+
+        vm.gc().unwrap();
+        assert_eq!(vm.collector().live_lines(), 1);
+        assert_eq!(vm.get_bytes(large), Ok(&[7; 16][..]));
+    }
+
+    #[test]
+    fn vectors_may_outgrow_their_place() {
+        let mut vm = test_vm(Immix::new());
+
+        let vector = vm.push_vector(0).unwrap();
+        for i in 0..BLOCK_SIZE {
+            vm.vector_push(vector, Value::Int(i as i64)).unwrap();
+        }
+        vm.gc().unwrap();
+
+        assert_eq!(vm.collector().live_lines(), LINES_PER_BLOCK);
+        assert_eq!(vm.vector_len(vector), Ok(BLOCK_SIZE));
+    }
+
+    #[test]
+    fn fresh_blocks_are_taken_when_holes_run_out() {
+        let mut vm = test_vm(Immix::new());
+
+        let mut last = vm.push_str("0").unwrap();
+        loop {
+            let obj = vm.push_str("0").unwrap();
+            vm.pop().unwrap();
+            if vm.collector().blocks() == 2 {
+                assert_eq!(offset(&vm, obj), BLOCK_SIZE);
+                assert!(end(&vm, last) + footprint(vm.heap().get(obj).unwrap()) > BLOCK_SIZE);
+                break;
+            }
+            last = obj;
+        }
+    }
+
+    #[test]
+    fn sparse_blocks_are_evacuated_while_marking() {
+        let mut vm = test_vm(Immix::new().evacuation_threshold(4));
+
+        let straggler = vm.push_str("7").unwrap();
+        fill_to(&mut vm, BLOCK_SIZE);
+        let dense: Vec<_> = (0..50)
+            .map(|i| vm.push_str(&i.to_string()).unwrap())
+            .collect();
+        assert!(offset(&vm, dense[0]) >= BLOCK_SIZE);
+
+        // The first collection finds the first block sparse, and the next
+        // one empties it.
+        vm.gc().unwrap();
+        assert_eq!(vm.collector().evacuated(), 0);
+        assert_eq!(offset(&vm, straggler), 0);
+
+        vm.gc().unwrap();
+        assert_eq!(vm.collector().evacuated(), 1);
+        let last = *dense.last().unwrap();
+        assert_eq!(
+            offset(&vm, straggler),
+            end(&vm, last).next_multiple_of(LINE_SIZE)
+        );
+        assert_eq!(vm.get_str(straggler), Ok("7"));
+
+        let obj = vm.push_str("0").unwrap();
+        assert_eq!(offset(&vm, obj), 0);
+    }
+
+    #[test]
+    fn evacuation_can_be_turned_off() {
+        let mut vm = test_vm(Immix::new().evacuation_threshold(0));
+
+        let straggler = vm.push_str("7").unwrap();
+        fill_to(&mut vm, BLOCK_SIZE);
+        vm.push_str("0").unwrap();
+
+        vm.gc().unwrap();
+        vm.gc().unwrap();
+
+        assert_eq!(vm.collector().evacuated(), 0);
+        assert_eq!(offset(&vm, straggler), 0);
+    }
+}
@@ -0,0 +1,181 @@
+use super::{schedule_steps, Collector, Stepping};
+use crate::error::VmError;
+use crate::heap::{Handle, Heap, Object};
+use crate::stats::GcReason;
+use crate::value::Value;
+
+/// Incremental tri-color mark-sweep collector.
+///
+/// White objects are unmarked, gray ones are marked and waiting on the
+/// worklist, black ones are marked and scanned. Marking is spread over
+/// bounded steps driven by allocation, with the mutator running in between.
+/// Two rules keep black objects from ever pointing at white ones while it
+/// does: objects allocated during marking start black with their fields
+/// shaded, and the write barrier shades every value stored into an object.
+///
+/// A cycle starts when one of the VM's thresholds is reached, and the VM
+/// collects to finish it once nothing is left to mark.
+#[derive(Default)]
+pub struct Incremental {
+    marking: bool,
+    gray: Vec<Handle>,
+    cycles: usize,
+}
+
+impl Incremental {
+    pub fn new() -> Self {
+        Incremental::default()
+    }
+
+    pub fn gray_len(&self) -> usize {
+        self.gray.len()
+    }
+
+    pub fn completed_cycles(&self) -> usize {
+        self.cycles
+    }
+
+    fn start_cycle(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        self.marking = true;
+        self.shade_roots(heap, roots)
+    }
+
+    fn shade_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        for root in roots.iter().filter_map(Value::as_object) {
+            Incremental::shade(&mut self.gray, heap, root)?;
+        }
+        Ok(())
+    }
+
+    fn shade(gray: &mut Vec<Handle>, heap: &mut Heap, handle: Handle) -> Result<(), VmError> {
+        if heap.mark(handle)? {
+            gray.push(handle);
+        }
+        Ok(())
+    }
+
+    fn blacken(&mut self, heap: &mut Heap, handle: Handle) -> Result<(), VmError> {
+        for child in heap.children(handle) {
+            Incremental::shade(&mut self.gray, heap, child)?;
+        }
+        Ok(())
+    }
+
+    fn finish_cycle(&mut self, heap: &mut Heap) {
+        heap.sweep();
+
+        self.marking = false;
+        self.cycles += 1;
+    }
+}
+
+impl Collector for Incremental {
+    fn allocate(&mut self, heap: &mut Heap, object: Object) -> Result<Handle, VmError> {
+        let handle = heap.insert(object)?;
+        if self.marking {
+            // Allocated black: it survives this cycle, so whatever it points
+            // to has to be reached as well.
+            heap.mark(handle)?;
+            self.blacken(heap, handle)?;
+        }
+        Ok(handle)
+    }
+
+    fn schedule(
+        &mut self,
+        heap: &mut Heap,
+        roots: &[Value],
+        due: Option<GcReason>,
+        step_budget: usize,
+    ) -> Result<Option<GcReason>, VmError> {
+        schedule_steps(self, heap, roots, due, step_budget)
+    }
+
+    fn scan_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        if self.marking {
+            self.shade_roots(heap, roots)
+        } else {
+            self.start_cycle(heap, roots)
+        }
+    }
+
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        while let Some(handle) = self.gray.pop() {
+            self.blacken(heap, handle)?;
+        }
+        self.finish_cycle(heap);
+        Ok(())
+    }
+
+    fn is_idle(&self) -> bool {
+        !self.marking
+    }
+
+    fn write_barrier(&mut self, heap: &mut Heap, _obj: Handle, _old: Value, new: Value) {
+        if let Some(new) = new.as_object().filter(|_| self.marking) {
+            // Every VM store runs `new` through check_value before the
+            // barrier, so shading cannot fail.
+            let _ = Incremental::shade(&mut self.gray, heap, new);
+        }
+    }
+}
+
+impl Stepping for Incremental {
+    fn step(&mut self, heap: &mut Heap, roots: &[Value], budget: usize) -> Result<usize, VmError> {
+        if !self.marking {
+            self.start_cycle(heap, roots)?;
+        }
+
+        let mut scanned = 0;
+        while scanned < budget {
+            let Some(handle) = self.gray.pop() else {
+                break;
+            };
+            self.blacken(heap, handle)?;
+            scanned += 1;
+        }
+
+        if self.gray.is_empty() {
+            // Roots pushed since the cycle started are still white.
+            self.shade_roots(heap, roots)?;
+        }
+
+        Ok(scanned)
+    }
+
+    fn is_marking(&self) -> bool {
+        self.marking
+    }
+
+    fn marking_done(&self) -> bool {
+        self.marking && self.gray.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::collector::collector_tests!(super::Incremental::new());
+    crate::collector::stepping_tests!(super::Incremental::new());
+
+    use super::Incremental;
+
+    #[test]
+    fn garbage_is_freed_when_the_cycle_ends() {
+        let mut vm = test_vm(Incremental::new());
+
+        for i in 0..4 {
+            vm.push_str(&i.to_string()).unwrap();
+        }
+        vm.pop().unwrap();
+        vm.pop().unwrap();
+
+        assert_eq!(vm.gc_step(1).unwrap(), 1);
+        assert_eq!(vm.collector().gray_len(), 1);
+        while vm.collector().is_marking() {
+            vm.gc_step(1).unwrap();
+        }
+
+        assert_eq!(vm.collector().completed_cycles(), 1);
+        assert_eq!(vm.heap().len(), 2);
+    }
+}
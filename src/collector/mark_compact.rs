@@ -0,0 +1,149 @@
+use super::mark_stack::MarkStack;
+use super::Collector;
+use crate::error::VmError;
+use crate::heap::Heap;
+use crate::value::Value;
+
+/// LISP2-style sliding mark-compact collector.
+///
+/// After marking, survivors are slid towards address zero in the order they
+/// sit in the heap, which is the order they were allocated in since
+/// allocation always bumps at the end. Free space ends up as one contiguous
+/// run past the last survivor.
+#[derive(Default)]
+pub struct MarkCompact {
+    mark_stack: MarkStack,
+    forwarding: Vec<Option<usize>>,
+}
+
+impl MarkCompact {
+    pub fn new() -> Self {
+        MarkCompact::default()
+    }
+
+    /// Gives every marked object the lowest address not taken by a marked
+    /// object below it. Returns the new extent of the heap.
+    fn compute_forwarding_addresses(&mut self, heap: &Heap) -> usize {
+        self.forwarding.clear();
+
+        let mut free = 0;
+        for addr in 0..heap.extent() {
+            if heap.object_at(addr).is_some() && heap.is_marked_at(addr) {
+                self.forwarding.push(Some(free));
+                free += 1;
+            } else {
+                self.forwarding.push(None);
+            }
+        }
+        free
+    }
+
+    /// Pairs and the root stack hold handles, so redirecting each handle's
+    /// entry to the forwarding address updates every reference to it.
+    fn update_references(&self, heap: &mut Heap) {
+        for (addr, forwarding) in self.forwarding.iter().enumerate() {
+            if let Some(new_addr) = *forwarding {
+                heap.forward(addr, new_addr);
+            }
+        }
+    }
+
+    fn relocate(&self, heap: &mut Heap) {
+        for (addr, forwarding) in self.forwarding.iter().enumerate() {
+            match *forwarding {
+                Some(new_addr) => heap.slide(addr, new_addr),
+                None => heap.free_at(addr),
+            }
+        }
+    }
+}
+
+impl Collector for MarkCompact {
+    fn scan_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        for root in roots.iter().filter_map(Value::as_object) {
+            self.mark_stack.mark(heap, root)?;
+        }
+        self.mark_stack.drain(heap)
+    }
+
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        let extent = self.compute_forwarding_addresses(heap);
+        self.update_references(heap);
+        self.relocate(heap);
+        heap.finish_compaction(extent);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::collector::collector_tests!(super::MarkCompact::new());
+
+    use crate::heap::ObjectType;
+
+    #[test]
+    fn survivors_keep_allocation_order() {
+        let mut vm = vm(10);
+
+        let mut kept = Vec::new();
+        for i in 0..8 {
+            let obj = vm.push_str(&i.to_string()).unwrap();
+            if i % 3 == 0 {
+                kept.push(obj);
+            } else {
+                vm.pop().unwrap();
+            }
+        }
+
+        vm.gc().unwrap();
+
+        let addresses: Vec<_> = kept
+            .iter()
+            .map(|&obj| vm.heap().address(obj).unwrap())
+            .collect();
+        assert_eq!(addresses, vec![0, 1, 2]);
+        assert_eq!(vm.heap().extent(), 3);
+    }
+
+    #[test]
+    fn free_space_is_contiguous() {
+        let mut vm = vm(10);
+
+        for i in 0..6 {
+            vm.push_str(&i.to_string()).unwrap();
+            if i % 2 == 0 {
+                vm.pop().unwrap();
+            }
+        }
+
+        vm.gc().unwrap();
+        let obj = vm.push_str("6").unwrap();
+
+        assert_eq!(vm.heap().address(obj), Some(3));
+        assert_eq!(vm.heap().extent(), 4);
+    }
+
+    #[test]
+    fn handles_stay_valid_after_compaction() {
+        let mut vm = vm(10);
+
+        vm.push_str("0").unwrap();
+        vm.pop().unwrap();
+        let one = vm.push_str("1").unwrap();
+        let two = vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+
+        vm.gc().unwrap();
+
+        assert_eq!(vm.heap().address(one), Some(0));
+        assert_eq!(vm.get_str(one), Ok("1"));
+        match &vm.heap().get(pair).unwrap().obj_type {
+            ObjectType::Pair(p) => {
+                assert_eq!(p.head, one.into());
+                assert_eq!(p.tail, two.into());
+                assert_eq!(vm.get_str(p.tail), Ok("2"));
+            }
+            _ => panic!("should be a pair"),
+        }
+    }
+}
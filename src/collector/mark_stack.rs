@@ -0,0 +1,123 @@
+use crate::error::VmError;
+use crate::heap::{Handle, Heap};
+
+/// Explicit worklist for marking, so deep structures such as long lists do
+/// not recurse on the native stack.
+///
+/// The stack holds at most `limit` entries. When it is full, newly marked
+/// objects are left unscanned and the overflow is remembered; once the stack
+/// drains, the heap is rescanned for marked objects with unmarked children
+/// and marking resumes from those.
+pub(crate) struct MarkStack {
+    entries: Vec<Handle>,
+    limit: usize,
+    overflowed: bool,
+    marked: usize,
+}
+
+impl Default for MarkStack {
+    fn default() -> Self {
+        MarkStack::with_limit(1 << 16)
+    }
+}
+
+impl MarkStack {
+    pub(crate) fn with_limit(limit: usize) -> Self {
+        MarkStack {
+            entries: Vec::new(),
+            limit: limit.max(1),
+            overflowed: false,
+            marked: 0,
+        }
+    }
+
+    /// Number of objects on the heap's object list marked since the last
+    /// call.
+    pub(crate) fn take_marked(&mut self) -> usize {
+        std::mem::take(&mut self.marked)
+    }
+
+    /// Marks `handle` and queues it for scanning if it was white.
+    pub(crate) fn mark(&mut self, heap: &mut Heap, handle: Handle) -> Result<(), VmError> {
+        self.mark_visiting(heap, handle, &mut |_, _| Ok(()))
+    }
+
+    /// Marks everything reachable from the queued objects.
+    pub(crate) fn drain(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        self.drain_visiting(heap, &mut |_, _| Ok(()))
+    }
+
+    /// Like [`MarkStack::mark`], but calls `visit` with the object if this
+    /// marked it, before any of its fields are scanned. `visit` may move the
+    /// object.
+    pub(crate) fn mark_visiting(
+        &mut self,
+        heap: &mut Heap,
+        handle: Handle,
+        visit: &mut impl FnMut(&mut Heap, Handle) -> Result<(), VmError>,
+    ) -> Result<(), VmError> {
+        if !heap.mark(handle)? {
+            return Ok(());
+        }
+
+        if !heap.in_large_space(handle) {
+            self.marked += 1;
+        }
+        visit(heap, handle)?;
+
+        if self.entries.len() < self.limit {
+            self.entries.push(handle);
+        } else {
+            self.overflowed = true;
+        }
+        Ok(())
+    }
+
+    /// Like [`MarkStack::drain`], but calls `visit` with every object it
+    /// marks, as [`MarkStack::mark_visiting`] does.
+    pub(crate) fn drain_visiting(
+        &mut self,
+        heap: &mut Heap,
+        visit: &mut impl FnMut(&mut Heap, Handle) -> Result<(), VmError>,
+    ) -> Result<(), VmError> {
+        let mut children = Vec::new();
+
+        loop {
+            while let Some(handle) = self.entries.pop() {
+                heap.get(handle)
+                    .ok_or(VmError::InvalidHandle)?
+                    .trace(|child| children.push(child));
+
+                for child in children.drain(..) {
+                    self.mark_visiting(heap, child, visit)?;
+                }
+            }
+
+            if !self.overflowed {
+                return Ok(());
+            }
+
+            self.overflowed = false;
+            self.rescan(heap, &mut children, visit)?;
+        }
+    }
+
+    fn rescan(
+        &mut self,
+        heap: &mut Heap,
+        children: &mut Vec<Handle>,
+        visit: &mut impl FnMut(&mut Heap, Handle) -> Result<(), VmError>,
+    ) -> Result<(), VmError> {
+        for addr in 0..heap.extent() {
+            match heap.object_at(addr) {
+                Some(obj) if heap.is_marked_at(addr) => obj.trace(|child| children.push(child)),
+                _ => continue,
+            }
+
+            for child in children.drain(..) {
+                self.mark_visiting(heap, child, visit)?;
+            }
+        }
+        Ok(())
+    }
+}
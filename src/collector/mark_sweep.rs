@@ -0,0 +1,316 @@
+use super::mark_stack::MarkStack;
+use super::{parallel_mark, Collector};
+use crate::error::VmError;
+use crate::heap::{Handle, Heap, Object, SweepCursor};
+use crate::value::Value;
+
+/// The classic stop-the-world collector: mark everything reachable from the
+/// roots, then sweep the object list.
+///
+/// In lazy-sweep mode a collection only marks. The sweep is spread over the
+/// following allocations, each of which sweeps a bounded number of objects
+/// first so it can reuse the cells they free, which keeps pauses
+/// proportional to the live data rather than to the heap.
+pub struct MarkSweep {
+    mark_stack: MarkStack,
+    mark_threads: usize,
+    lazy_budget: Option<usize>,
+    sweep: Option<SweepCursor>,
+    marked: usize,
+    unswept_garbage: usize,
+}
+
+impl Default for MarkSweep {
+    fn default() -> Self {
+        MarkSweep {
+            mark_stack: MarkStack::default(),
+            mark_threads: 1,
+            lazy_budget: None,
+            sweep: None,
+            marked: 0,
+            unswept_garbage: 0,
+        }
+    }
+}
+
+impl MarkSweep {
+    pub fn new() -> Self {
+        MarkSweep::default()
+    }
+
+    /// Bounds the marking worklist. Marking still completes when it fills
+    /// up, at the cost of rescanning the heap.
+    pub fn mark_stack_limit(mut self, limit: usize) -> Self {
+        self.mark_stack = MarkStack::with_limit(limit);
+        self
+    }
+
+    /// Number of threads marking in parallel. With more than one, the roots
+    /// are split between the threads, which steal work from each other.
+    pub fn mark_threads(mut self, threads: usize) -> Self {
+        self.mark_threads = threads.max(1);
+        self
+    }
+
+    /// Sweeps lazily, visiting at most `budget` objects per allocation.
+    pub fn lazy_sweep(mut self, budget: usize) -> Self {
+        self.lazy_budget = Some(budget.max(1));
+        self
+    }
+
+    /// Whether garbage found by the last collection is still waiting to be
+    /// swept.
+    pub fn is_sweeping(&self) -> bool {
+        self.sweep.is_some()
+    }
+
+    fn sweep_step(&mut self, heap: &mut Heap, budget: usize) {
+        let Some(cursor) = &mut self.sweep else {
+            return;
+        };
+
+        let freed = heap.sweep_step(cursor, budget);
+        self.unswept_garbage -= freed;
+
+        if cursor.is_done() {
+            debug_assert_eq!(self.unswept_garbage, 0);
+            self.sweep = None;
+        }
+    }
+}
+
+impl Collector for MarkSweep {
+    fn allocate(&mut self, heap: &mut Heap, object: Object) -> Result<Handle, VmError> {
+        if let Some(budget) = self.lazy_budget {
+            self.sweep_step(heap, budget);
+        }
+
+        let handle = heap.insert(object)?;
+        if self.sweep.is_some() {
+            // The pending sweep never reaches objects allocated after it
+            // started, and its epoch flip would leave them marked.
+            heap.mark(handle)?;
+        }
+        Ok(handle)
+    }
+
+    fn scan_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        // The mark bits only reset when the pending sweep completes.
+        self.sweep_step(heap, usize::MAX);
+
+        if self.mark_threads > 1 {
+            self.marked = parallel_mark::mark(heap, roots, self.mark_threads)?;
+            return Ok(());
+        }
+
+        for root in roots.iter().filter_map(Value::as_object) {
+            self.mark_stack.mark(heap, root)?;
+        }
+        self.mark_stack.drain(heap)?;
+        self.marked = self.mark_stack.take_marked();
+        Ok(())
+    }
+
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        if self.lazy_budget.is_some() {
+            self.unswept_garbage = heap.len() - self.marked;
+            self.sweep = Some(heap.sweep_cursor());
+        } else {
+            heap.sweep();
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        self.sweep_step(heap, usize::MAX);
+        Ok(())
+    }
+
+    fn live_objects(&self, heap: &Heap) -> usize {
+        heap.len() - self.unswept_garbage
+    }
+
+    fn is_live(&self, heap: &Heap, handle: Handle) -> bool {
+        // Everything the pending sweep keeps is marked, including objects
+        // allocated since it started.
+        heap.contains(handle) && (!self.is_sweeping() || heap.is_marked(handle))
+    }
+
+    fn is_idle(&self) -> bool {
+        !self.is_sweeping()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::collector::collector_tests!(super::MarkSweep::new());
+
+    use super::MarkSweep;
+
+    mod parallel {
+        crate::collector::collector_tests!(super::MarkSweep::new().mark_threads(4));
+
+        use super::MarkSweep;
+
+        #[test]
+        fn marks_wide_heaps_in_parallel() {
+            let mut vm = VM::with_collector(
+                GcConfig::new().stack_size(64).initial_threshold(1 << 20),
+                MarkSweep::new().mark_threads(4),
+            );
+
+            for root in 0..32 {
+                vm.push_str(&root.to_string()).unwrap();
+                for i in 0..500 {
+                    vm.push_str(&i.to_string()).unwrap();
+                    vm.push_pair().unwrap();
+                }
+                vm.push_str(&root.to_string()).unwrap();
+                vm.pop().unwrap();
+            }
+
+            vm.gc().unwrap();
+
+            assert_eq!(vm.heap().len(), 32 * 1001);
+        }
+    }
+
+    mod lazy {
+        crate::collector::collector_tests!(super::MarkSweep::new().lazy_sweep(4));
+
+        use super::MarkSweep;
+        use crate::collector::{test_vm, Collector};
+        use crate::error::VmError;
+
+        fn lazy(budget: usize) -> VM<MarkSweep> {
+            test_vm(MarkSweep::new().lazy_sweep(budget))
+        }
+
+        #[test]
+        fn gc_only_marks() {
+            let mut vm = lazy(2);
+
+            for i in 0..10 {
+                vm.push_str(&i.to_string()).unwrap();
+            }
+            for _ in 0..6 {
+                vm.pop().unwrap();
+            }
+
+            vm.gc().unwrap();
+
+            assert!(vm.collector().is_sweeping());
+            assert_eq!(vm.heap().len(), 10);
+            assert_eq!(vm.collector().live_objects(vm.heap()), 4);
+        }
+
+        #[test]
+        fn allocation_sweeps_and_reuses_dead_cells() {
+            let mut vm = lazy(4);
+
+            for i in 0..10 {
+                vm.push_str(&i.to_string()).unwrap();
+            }
+            for _ in 0..6 {
+                vm.pop().unwrap();
+            }
+            vm.gc().unwrap();
+            let extent = vm.heap().extent();
+
+            for i in 0..3 {
+                vm.push_str(&i.to_string()).unwrap();
+            }
+
+            assert!(!vm.collector().is_sweeping());
+            assert_eq!(vm.heap().len(), 7);
+            assert_eq!(vm.heap().extent(), extent);
+        }
+
+        #[test]
+        fn collecting_again_finishes_the_pending_sweep() {
+            let mut vm = lazy(1);
+
+            for i in 0..6 {
+                vm.push_str(&i.to_string()).unwrap();
+            }
+            for _ in 0..3 {
+                vm.pop().unwrap();
+            }
+            vm.gc().unwrap();
+            vm.pop().unwrap();
+            vm.gc().unwrap();
+
+            assert_eq!(vm.collector().live_objects(vm.heap()), 2);
+
+            vm.push_str("6").unwrap();
+            vm.push_str("7").unwrap();
+            vm.push_str("8").unwrap();
+
+            assert!(!vm.collector().is_sweeping());
+            assert_eq!(vm.heap().len(), 5);
+        }
+
+        #[test]
+        fn unswept_garbage_cannot_be_reused() {
+            let mut vm = lazy(1);
+
+            vm.push_str("1").unwrap();
+            vm.push_str("2").unwrap();
+            let pair = vm.push_pair().unwrap();
+            let garbage = vm.push_str("3").unwrap();
+            vm.pop().unwrap();
+            vm.gc().unwrap();
+
+            assert!(vm.collector().is_sweeping());
+            assert_eq!(vm.set_pair_tail(pair, garbage), Err(VmError::InvalidHandle));
+
+            let young = vm.push_str("4").unwrap();
+            assert!(vm.collector().is_sweeping());
+            vm.set_pair_tail(pair, young).unwrap();
+
+            vm.finish_gc().unwrap();
+            assert!(!vm.heap().contains(garbage));
+            assert!(vm.heap().iter().all(|(h, _)| !vm.heap().is_marked(h)));
+        }
+    }
+
+    #[test]
+    fn marks_a_million_element_list() {
+        let mut vm = VM::with_config(GcConfig::new().stack_size(4));
+
+        vm.push_str("0").unwrap();
+        for i in 1..1_000_000 {
+            vm.push_str(&i.to_string()).unwrap();
+            vm.push_pair().unwrap();
+        }
+
+        vm.gc().unwrap();
+        assert_eq!(vm.heap().len(), 1_999_999);
+
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+        assert!(vm.heap().is_empty());
+    }
+
+    #[test]
+    fn mark_stack_overflow_falls_back_to_rescanning() {
+        let mut vm = VM::with_collector(
+            GcConfig::new().stack_size(10),
+            MarkSweep::new().mark_stack_limit(1),
+        );
+
+        vm.push_str("0").unwrap();
+        for i in 1..20 {
+            vm.push_str(&i.to_string()).unwrap();
+            vm.push_str(&i.to_string()).unwrap();
+            vm.push_pair().unwrap();
+            vm.push_pair().unwrap();
+        }
+        vm.push_str("99").unwrap();
+        vm.pop().unwrap();
+
+        vm.gc().unwrap();
+
+        assert_eq!(vm.heap().len(), 1 + 19 * 4);
+    }
+}
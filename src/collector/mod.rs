@@ -0,0 +1,558 @@
+mod concurrent;
+mod copying;
+mod generational;
+mod immix;
+mod incremental;
+mod mark_compact;
+mod mark_stack;
+mod mark_sweep;
+mod parallel_mark;
+mod ref_count;
+mod treadmill;
+
+pub use concurrent::Concurrent;
+pub use copying::SemiSpace;
+pub use generational::Generational;
+pub use immix::Immix;
+pub use incremental::Incremental;
+pub use mark_compact::MarkCompact;
+pub use mark_sweep::MarkSweep;
+pub use ref_count::RefCount;
+pub use treadmill::Treadmill;
+
+use crate::error::VmError;
+use crate::heap::{Handle, Heap, Object};
+use crate::stats::GcReason;
+use crate::value::Value;
+
+/// A garbage collection strategy.
+///
+/// The [`VM`](crate::VM) owns the heap and the root stack and decides when a
+/// collection is due; the collector decides where objects go and how
+/// unreachable ones are found and released.
+pub trait Collector {
+    /// Places a new object on the heap.
+    fn allocate(&mut self, heap: &mut Heap, object: Object) -> Result<Handle, VmError> {
+        heap.insert(object)
+    }
+
+    /// Called before every allocation with the collection the VM's
+    /// threshold calls for, if any, and returns the one to run. Collectors
+    /// that mark in steps start or advance a cycle here instead, scanning up
+    /// to `step_budget` objects, and ask for a [`GcReason::Scheduled`]
+    /// collection to finish it once nothing is left to mark.
+    fn schedule(
+        &mut self,
+        heap: &mut Heap,
+        roots: &[Value],
+        due: Option<GcReason>,
+        step_budget: usize,
+    ) -> Result<Option<GcReason>, VmError> {
+        let _ = (heap, roots, step_budget);
+        Ok(due)
+    }
+
+    /// Starts a collection from the VM's roots.
+    fn scan_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError>;
+
+    /// Finishes the collection started by [`Collector::scan_roots`],
+    /// releasing every object that was not reached.
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError>;
+
+    /// Like [`Collector::scan_roots`], for a collection the collector
+    /// scheduled itself.
+    fn scan_scheduled(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        self.scan_roots(heap, roots)
+    }
+
+    /// Like [`Collector::collect`], for a collection the collector
+    /// scheduled itself.
+    fn collect_scheduled(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        self.collect(heap)
+    }
+
+    /// Completes work the last collection deferred, such as lazy sweeping.
+    fn finish(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        let _ = heap;
+        Ok(())
+    }
+
+    /// Number of objects on the heap that are not known to be garbage.
+    /// Collectors that release memory lazily count garbage they have found
+    /// but not freed yet as dead.
+    fn live_objects(&self, heap: &Heap) -> usize {
+        heap.len()
+    }
+
+    /// Whether the mutator may still use `handle`, to store it or push it.
+    /// Collectors that release memory lazily reject garbage they have found
+    /// but not freed yet, which may already refer to freed objects.
+    fn is_live(&self, heap: &Heap, handle: Handle) -> bool {
+        heap.contains(handle)
+    }
+
+    /// Number of objects the VM's collection threshold counts. Collectors
+    /// that collect part of the heap on their own schedule leave that part
+    /// out.
+    fn threshold_objects(&self, heap: &Heap) -> usize {
+        self.live_objects(heap)
+    }
+
+    /// Called after the mutator overwrote a field of `obj`, replacing `old`
+    /// with `new`.
+    fn write_barrier(&mut self, heap: &mut Heap, obj: Handle, old: Value, new: Value) {
+        let _ = (heap, obj, old, new);
+    }
+
+    /// Whether the collector has nothing in progress or left over, such as
+    /// a running marking cycle, a pending sweep or garbage it found but has
+    /// not released. Every object on the heap is then unmarked and only
+    /// refers to objects on the heap, which the heap verifier checks.
+    fn is_idle(&self) -> bool {
+        true
+    }
+
+    /// Called after `handle` was pushed on the VM stack.
+    fn root_pushed(&mut self, heap: &mut Heap, handle: Handle) {
+        let _ = (heap, handle);
+    }
+
+    /// Called after `handle` was popped off the VM stack.
+    fn root_popped(&mut self, heap: &mut Heap, handle: Handle) {
+        let _ = (heap, handle);
+    }
+}
+
+/// A collector that marks in bounded steps, with the mutator running in
+/// between. [`VM::gc_step`](crate::VM::gc_step) drives it by hand.
+pub trait Stepping: Collector {
+    /// Advances the current marking cycle, starting one if none is running,
+    /// by scanning at most `budget` objects. Returns how many were scanned.
+    fn step(&mut self, heap: &mut Heap, roots: &[Value], budget: usize) -> Result<usize, VmError>;
+
+    /// Whether a marking cycle is running.
+    fn is_marking(&self) -> bool;
+
+    /// Whether the running cycle has nothing left to mark, so a collection
+    /// can finish it.
+    fn marking_done(&self) -> bool;
+}
+
+/// [`Collector::schedule`] for collectors that mark in steps: the threshold
+/// starts a cycle rather than a full collection, and every allocation while
+/// it runs advances it until the VM can collect to finish it.
+pub(crate) fn schedule_steps(
+    collector: &mut impl Stepping,
+    heap: &mut Heap,
+    roots: &[Value],
+    due: Option<GcReason>,
+    step_budget: usize,
+) -> Result<Option<GcReason>, VmError> {
+    if due.is_some() || collector.is_marking() {
+        collector.step(heap, roots, step_budget)?;
+    }
+    Ok(collector.marking_done().then_some(GcReason::Scheduled))
+}
+
+/// A VM for collector tests, with room on the stack and thresholds high
+/// enough that collections only happen when the test asks for one.
+#[cfg(test)]
+pub(crate) fn test_vm<C: Collector>(collector: C) -> crate::vm::VM<C> {
+    crate::vm::VM::with_collector(
+        crate::config::GcConfig::new()
+            .stack_size(64)
+            .initial_threshold(usize::MAX)
+            .byte_threshold(usize::MAX),
+        collector,
+    )
+}
+
+/// Mutator scenarios every collector has to pass. Invoke inside a test
+/// module with an expression building a fresh collector.
+#[cfg(test)]
+macro_rules! collector_tests {
+    ($collector:expr) => {
+        use crate::config::GcConfig;
+        use crate::heap::dropped_objects;
+        use crate::vm::VM;
+
+        fn vm(stack_size: usize) -> VM<impl crate::collector::Collector> {
+            VM::with_collector(GcConfig::new().stack_size(stack_size), $collector)
+        }
+
+        fn full_gc(vm: &mut VM<impl crate::collector::Collector>) {
+            vm.gc().unwrap();
+            vm.finish_gc().unwrap();
+        }
+
+        #[test]
+        fn stack_objects_are_preserved() {
+            let mut vm = vm(10);
+
+            vm.push_str("1").unwrap();
+            vm.push_str("2").unwrap();
+
+            full_gc(&mut vm);
+
+            assert_eq!(vm.heap().len(), 2);
+        }
+
+        #[test]
+        fn unreached_objects_are_collected() {
+            let mut vm = vm(10);
+
+            vm.push_str("1").unwrap();
+            vm.push_str("2").unwrap();
+
+            vm.pop().unwrap();
+            vm.pop().unwrap();
+
+            full_gc(&mut vm);
+
+            assert_eq!(vm.heap().len(), 0);
+        }
+
+        #[test]
+        fn nested_objects_are_reachable() {
+            let mut vm = vm(10);
+
+            vm.push_str("1").unwrap();
+            vm.push_str("2").unwrap();
+            vm.push_pair().unwrap();
+            vm.push_str("3").unwrap();
+            vm.push_str("4").unwrap();
+            vm.push_pair().unwrap();
+            vm.push_pair().unwrap();
+
+            full_gc(&mut vm);
+
+            assert_eq!(vm.heap().len(), 7);
+        }
+
+        #[test]
+        fn handles_cycles() {
+            let mut vm = vm(10);
+
+            vm.push_str("1").unwrap();
+            vm.push_str("2").unwrap();
+            let a = vm.push_pair().unwrap();
+            vm.push_str("3").unwrap();
+            vm.push_str("4").unwrap();
+            let b = vm.push_pair().unwrap();
+
+            vm.set_pair_tail(a, b).unwrap();
+            vm.set_pair_tail(b, a).unwrap();
+
+            full_gc(&mut vm);
+
+            assert_eq!(vm.heap().len(), 4);
+        }
+
+        #[test]
+        fn unreachable_cycles_are_freed() {
+            let before = dropped_objects();
+            let mut vm = vm(10);
+
+            vm.push_str("1").unwrap();
+            vm.push_str("2").unwrap();
+            let a = vm.push_pair().unwrap();
+            vm.push_str("3").unwrap();
+            vm.push_str("4").unwrap();
+            let b = vm.push_pair().unwrap();
+
+            vm.set_pair_tail(a, b).unwrap();
+            vm.set_pair_tail(b, a).unwrap();
+
+            vm.pop().unwrap();
+            vm.pop().unwrap();
+
+            full_gc(&mut vm);
+
+            assert_eq!(dropped_objects() - before, 6);
+            assert!(vm.heap().is_empty());
+            assert_eq!(vm.heap().first_object(), None);
+            assert!(vm.heap().get(a).is_none());
+        }
+
+        #[test]
+        fn survives_allocation_pressure() {
+            let mut vm = vm(64);
+
+            let mut kept = Vec::new();
+            for i in 0..500 {
+                let obj = vm.push_str(&i.to_string()).unwrap();
+                if i % 50 == 0 {
+                    kept.push((obj, i));
+                    vm.push_str(&i.to_string()).unwrap();
+                    vm.push_pair().unwrap();
+                } else {
+                    vm.pop().unwrap();
+                }
+            }
+
+            full_gc(&mut vm);
+
+            assert_eq!(vm.heap().len(), kept.len() * 3);
+            for (obj, i) in kept {
+                assert_eq!(vm.get_str(obj), Ok(i.to_string().as_str()));
+            }
+        }
+
+        #[test]
+        fn vector_elements_are_traced() {
+            let mut vm = vm(10);
+
+            let vector = vm.push_vector(1).unwrap();
+            for i in 0..3 {
+                let element = vm.push_str(&i.to_string()).unwrap();
+                vm.vector_push(vector, element).unwrap();
+                vm.pop().unwrap();
+            }
+            let replaced = vm.vector_get(vector, 1).unwrap();
+            let element = vm.push_str("new").unwrap();
+            vm.vector_set(vector, 1, element).unwrap();
+            vm.pop().unwrap();
+            vm.vector_push(vector, vector).unwrap();
+
+            full_gc(&mut vm);
+
+            assert_eq!(vm.heap().len(), 4);
+            assert!(!vm.heap().contains(replaced.as_object().unwrap()));
+            let elements: Vec<_> = (0..3)
+                .map(|i| vm.get_str(vm.vector_get(vector, i).unwrap()).unwrap())
+                .collect();
+            assert_eq!(elements, ["0", "new", "2"]);
+            assert_eq!(vm.vector_get(vector, 3), Ok(vector.into()));
+
+            vm.pop().unwrap();
+            full_gc(&mut vm);
+            assert!(vm.heap().is_empty());
+        }
+
+        #[test]
+        fn map_entries_are_traced() {
+            let mut vm = vm(10);
+
+            let map = vm.push_map(crate::map::MapMode::Identity).unwrap();
+            let key = vm.push_str("key").unwrap();
+            let value = vm.push_str("value").unwrap();
+            vm.map_insert(map, key, value).unwrap();
+            let removed = vm.push_str("removed").unwrap();
+            vm.map_insert(map, crate::value::Value::Int(1), removed)
+                .unwrap();
+            for _ in 0..3 {
+                vm.pop().unwrap();
+            }
+            vm.map_remove(map, crate::value::Value::Int(1)).unwrap();
+
+            full_gc(&mut vm);
+
+            assert_eq!(vm.heap().len(), 3);
+            assert!(!vm.heap().contains(removed));
+            let found = vm.map_get(map, key).unwrap().unwrap();
+            assert_eq!(vm.get_str(found), Ok("value"));
+
+            vm.pop().unwrap();
+            full_gc(&mut vm);
+            assert!(vm.heap().is_empty());
+        }
+
+        #[test]
+        fn large_objects_are_swept_separately() {
+            let mut vm = vm(10);
+            let large = crate::heap::LARGE_OBJECT_SIZE;
+
+            let kept = vm.push_bytes(&vec![1; large]).unwrap();
+            vm.push_int(0).unwrap();
+            vm.push_pair().unwrap();
+            let dropped = vm.push_bytes(&vec![2; large]).unwrap();
+            vm.pop().unwrap();
+            let small = vm.push_bytes(&[3]).unwrap();
+
+            full_gc(&mut vm);
+
+            assert!(vm.heap().in_large_space(kept));
+            assert!(!vm.heap().in_large_space(small));
+            assert!(!vm.heap().contains(dropped));
+            assert_eq!(vm.heap().address(kept), None);
+            assert_eq!(vm.heap().len(), 2);
+            assert_eq!(vm.heap().large_len(), 1);
+            assert_eq!(vm.get_bytes(kept).unwrap(), &vec![1; large][..]);
+            assert!(vm.verify_heap().is_empty());
+
+            vm.pop().unwrap();
+            vm.pop().unwrap();
+            full_gc(&mut vm);
+            assert!(vm.heap().is_empty());
+            assert_eq!(vm.heap().large_len(), 0);
+            assert_eq!(vm.heap().bytes(), 0);
+        }
+
+        #[test]
+        fn heap_stays_consistent() {
+            let config = GcConfig::new()
+                .stack_size(64)
+                .initial_threshold(4)
+                .verify_heap(true);
+            let mut vm = VM::with_collector(config, $collector);
+
+            for i in 0..200 {
+                vm.push_str(&i.to_string()).unwrap();
+                vm.push_int(i).unwrap();
+                let pair = vm.push_pair().unwrap();
+                if i % 5 == 0 {
+                    vm.push(pair).unwrap();
+                    vm.pop().unwrap();
+                }
+                if i % 3 == 0 {
+                    vm.push_str(&i.to_string()).unwrap();
+                    vm.push_pair().unwrap();
+                    vm.set_pair_tail(pair, pair).unwrap();
+                }
+                if i % 20 != 0 {
+                    vm.pop().unwrap();
+                }
+            }
+
+            full_gc(&mut vm);
+            assert_eq!(vm.verify_heap(), vec![]);
+        }
+    };
+}
+
+#[cfg(test)]
+pub(crate) use collector_tests;
+
+/// Scenarios every [`Stepping`] collector has to pass, on top of
+/// [`collector_tests`]. Invoke next to it with the same expression.
+#[cfg(test)]
+macro_rules! stepping_tests {
+    ($collector:expr) => {
+        use crate::collector::{test_vm, Stepping};
+
+        fn stepping(config: GcConfig) -> VM<impl Stepping> {
+            VM::with_collector(config.stack_size(64).step_budget(1), $collector)
+        }
+
+        /// Allocates until a marking cycle starts and returns how many live
+        /// objects there were when it did.
+        fn objects_at_next_cycle(vm: &mut VM<impl Stepping>) -> usize {
+            loop {
+                let objects = vm.collector().live_objects(vm.heap());
+                vm.push_str(&objects.to_string()).unwrap();
+                if vm.collector().is_marking() {
+                    return objects;
+                }
+            }
+        }
+
+        #[test]
+        fn steps_respect_the_budget() {
+            let mut vm = test_vm($collector);
+
+            for i in 0..10 {
+                vm.push_str(&i.to_string()).unwrap();
+            }
+
+            assert_eq!(vm.gc_step(3).unwrap(), 3);
+            assert!(vm.collector().is_marking());
+
+            assert_eq!(vm.gc_step(100).unwrap(), 7);
+            assert!(!vm.collector().is_marking());
+            assert_eq!(vm.heap().len(), 10);
+        }
+
+        #[test]
+        fn write_barrier_preserves_the_tri_color_invariant() {
+            let mut vm = test_vm($collector);
+
+            vm.push_str("1").unwrap();
+            let a_tail = vm.push_str("2").unwrap();
+            let a = vm.push_pair().unwrap();
+            vm.push_str("3").unwrap();
+            let b_tail = vm.push_str("4").unwrap();
+            let b = vm.push_pair().unwrap();
+
+            // One step blackens one pair, leaving the other gray with its
+            // fields still white.
+            vm.gc_step(1).unwrap();
+            let (black, gray, x) = if vm.heap().is_marked(a_tail) {
+                (a, b, b_tail)
+            } else {
+                (b, a, a_tail)
+            };
+            assert!(!vm.heap().is_marked(x));
+
+            vm.set_pair_tail(black, x).unwrap();
+            vm.set_pair_tail(gray, black).unwrap();
+
+            while vm.collector().is_marking() {
+                vm.gc_step(1).unwrap();
+            }
+            vm.finish_gc().unwrap();
+
+            assert!(vm.heap().contains(x));
+        }
+
+        #[test]
+        fn allocation_drives_cycles() {
+            let mut vm = stepping(GcConfig::new().initial_threshold(4));
+
+            for i in 0..40 {
+                vm.push_str(&i.to_string()).unwrap();
+                vm.pop().unwrap();
+            }
+
+            assert!(vm.heap().len() < 40);
+        }
+
+        #[test]
+        fn initial_threshold_starts_the_first_cycle() {
+            let mut vm = stepping(GcConfig::new().initial_threshold(5));
+
+            assert_eq!(objects_at_next_cycle(&mut vm), 5);
+        }
+
+        #[test]
+        fn growth_factor_paces_the_next_cycle() {
+            let mut vm = stepping(
+                GcConfig::new()
+                    .initial_threshold(4)
+                    .growth_factor(3.0)
+                    .min_threshold(1),
+            );
+
+            objects_at_next_cycle(&mut vm);
+            vm.gc_step(usize::MAX).unwrap();
+            assert_eq!(vm.heap().len(), 5);
+
+            assert_eq!(objects_at_next_cycle(&mut vm), 15);
+        }
+
+        #[test]
+        fn min_threshold_paces_the_next_cycle() {
+            let mut vm = stepping(GcConfig::new().min_threshold(6));
+
+            vm.push_str("1").unwrap();
+            vm.pop().unwrap();
+            vm.gc().unwrap();
+
+            assert_eq!(objects_at_next_cycle(&mut vm), 6);
+        }
+
+        #[test]
+        fn max_threshold_paces_the_next_cycle() {
+            let mut vm = stepping(GcConfig::new().initial_threshold(1000).max_threshold(10));
+
+            for i in 0..20 {
+                vm.push_str(&i.to_string()).unwrap();
+            }
+            vm.gc().unwrap();
+
+            assert_eq!(objects_at_next_cycle(&mut vm), 20);
+        }
+    };
+}
+
+#[cfg(test)]
+pub(crate) use stepping_tests;
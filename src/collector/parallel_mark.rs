@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::error::VmError;
+use crate::heap::{Handle, Heap};
+use crate::value::Value;
+
+/// Shared state of one parallel mark phase.
+struct Workers<'a> {
+    heap: &'a Heap,
+    marked: AtomicUsize,
+    deques: Vec<Mutex<VecDeque<Handle>>>,
+    /// Objects queued or being scanned. Children are queued before their
+    /// parent is accounted for, so this only reaches zero once marking is
+    /// complete.
+    outstanding: AtomicUsize,
+    failed: AtomicBool,
+}
+
+impl Workers<'_> {
+    fn shade(&self, id: usize, handle: Handle) {
+        match self.heap.mark_shared(handle) {
+            Ok(true) => {
+                if !self.heap.in_large_space(handle) {
+                    self.marked.fetch_add(1, Ordering::Relaxed);
+                }
+                self.outstanding.fetch_add(1, Ordering::AcqRel);
+                self.deques[id].lock().unwrap().push_back(handle);
+            }
+            Ok(false) => {}
+            Err(_) => self.failed.store(true, Ordering::Release),
+        }
+    }
+
+    fn next(&self, id: usize) -> Option<Handle> {
+        if let Some(handle) = self.deques[id].lock().unwrap().pop_back() {
+            return Some(handle);
+        }
+
+        // Steal the oldest entry of another worker; those tend to be the
+        // roots of the largest unexplored subgraphs.
+        let n = self.deques.len();
+        (1..n).find_map(|offset| self.deques[(id + offset) % n].lock().unwrap().pop_front())
+    }
+
+    fn run(&self, id: usize) {
+        loop {
+            match self.next(id) {
+                Some(handle) => {
+                    match self.heap.get(handle) {
+                        Some(obj) => obj.trace(|child| self.shade(id, child)),
+                        None => self.failed.store(true, Ordering::Release),
+                    }
+                    self.outstanding.fetch_sub(1, Ordering::AcqRel);
+                }
+                None if self.outstanding.load(Ordering::Acquire) == 0 => return,
+                None => thread::yield_now(),
+            }
+        }
+    }
+}
+
+/// Marks everything reachable from `roots` using `threads` workers, each
+/// with its own deque and stealing from the others when it runs dry. The
+/// roots are dealt out round-robin. Returns how many objects on the object
+/// list were marked.
+pub(crate) fn mark(heap: &Heap, roots: &[Value], threads: usize) -> Result<usize, VmError> {
+    let threads = threads.max(1);
+    let workers = Workers {
+        heap,
+        marked: AtomicUsize::new(0),
+        deques: (0..threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+        outstanding: AtomicUsize::new(0),
+        failed: AtomicBool::new(false),
+    };
+
+    for (i, root) in roots.iter().filter_map(Value::as_object).enumerate() {
+        workers.shade(i % threads, root);
+    }
+
+    thread::scope(|scope| {
+        for id in 0..threads {
+            let workers = &workers;
+            scope.spawn(move || workers.run(id));
+        }
+    });
+
+    if workers.failed.load(Ordering::Acquire) {
+        return Err(VmError::InvalidHandle);
+    }
+
+    Ok(workers.marked.into_inner())
+}
@@ -0,0 +1,429 @@
+use super::Collector;
+use crate::error::VmError;
+use crate::heap::{Handle, Heap, Object};
+use crate::value::Value;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Color {
+    /// In use or free.
+    Black,
+    /// Possible member of a garbage cycle.
+    Gray,
+    /// Member of a garbage cycle.
+    White,
+    /// Possible root of a garbage cycle.
+    Purple,
+}
+
+#[derive(Clone, Copy)]
+struct Info {
+    /// Generation of the handle the entry belongs to.
+    generation: u32,
+    count: u32,
+    color: Color,
+    buffered: bool,
+}
+
+impl Default for Info {
+    fn default() -> Self {
+        Info {
+            generation: 0,
+            count: 0,
+            color: Color::Black,
+            buffered: false,
+        }
+    }
+}
+
+/// Reference counting with synchronous cycle collection after Bacon and
+/// Rajan.
+///
+/// References from object fields and from the VM stack are counted, and an
+/// object is freed the moment its count drops to zero. An object whose count
+/// drops to something else might have just become part of a garbage cycle,
+/// so it is buffered as a candidate root. A collection then runs trial
+/// deletion over the candidates: subtract the references coming from inside
+/// the subgraph below them, restore the counts of whatever is still
+/// referenced from outside, and free the rest.
+#[derive(Default)]
+pub struct RefCount {
+    info: Vec<Info>,
+    candidates: Vec<Handle>,
+    /// Candidates whose count dropped to zero, waiting to be freed.
+    released: usize,
+}
+
+impl RefCount {
+    pub fn new() -> Self {
+        RefCount::default()
+    }
+
+    /// Number of references to the object, or `None` if it was freed.
+    pub fn count(&self, heap: &Heap, handle: Handle) -> Option<u32> {
+        heap.contains(handle)
+            .then(|| self.info[handle.index()].count)
+    }
+
+    /// Objects buffered as possible roots of garbage cycles.
+    pub fn candidates(&self) -> &[Handle] {
+        &self.candidates
+    }
+
+    fn info(&mut self, handle: Handle) -> &mut Info {
+        let info = &mut self.info[handle.index()];
+        debug_assert_eq!(info.generation, handle.generation());
+        info
+    }
+
+    /// Like [`RefCount::info`], but `None` for a stale handle whose index
+    /// now belongs to a newer object.
+    fn current(&mut self, handle: Handle) -> Option<&mut Info> {
+        self.info
+            .get_mut(handle.index())
+            .filter(|info| info.generation == handle.generation())
+    }
+
+    /// Whether the object is a buffered candidate whose count dropped to
+    /// zero. It is garbage, and the references it holds were released
+    /// already, but it stays on the heap until the candidates are
+    /// processed.
+    fn is_released(&self, handle: Handle) -> bool {
+        let info = &self.info[handle.index()];
+        info.buffered && info.count == 0
+    }
+
+    fn increment(&mut self, handle: Handle) {
+        if let Some(info) = self.current(handle) {
+            info.count += 1;
+            info.color = Color::Black;
+        }
+    }
+
+    fn decrement(&mut self, heap: &mut Heap, handle: Handle) {
+        let mut pending = vec![handle];
+
+        while let Some(handle) = pending.pop() {
+            let Some(info) = self.current(handle) else {
+                continue;
+            };
+            if info.count == 0 {
+                // Released before; its references went with it.
+                continue;
+            }
+            info.count -= 1;
+
+            if info.count > 0 {
+                self.possible_root(heap, handle);
+                continue;
+            }
+
+            // Release: the object is garbage, and so is every reference it
+            // holds. Buffered objects are freed when the candidates are
+            // processed instead, since the buffer still refers to them.
+            pending.extend(heap.children(handle));
+            let info = self.info(handle);
+            info.color = Color::Black;
+            if info.buffered {
+                self.released += 1;
+            } else {
+                heap.free(handle);
+            }
+        }
+    }
+
+    fn possible_root(&mut self, heap: &Heap, handle: Handle) {
+        // Objects without references cannot be part of a cycle.
+        if heap.children(handle).is_empty() {
+            return;
+        }
+
+        let info = self.info(handle);
+        if info.color != Color::Purple {
+            info.color = Color::Purple;
+            if !info.buffered {
+                info.buffered = true;
+                self.candidates.push(handle);
+            }
+        }
+    }
+
+    fn collect_cycles(&mut self, heap: &mut Heap) {
+        self.mark_roots(heap);
+        for i in 0..self.candidates.len() {
+            self.scan(heap, self.candidates[i]);
+        }
+        self.collect_roots(heap);
+    }
+
+    fn mark_roots(&mut self, heap: &mut Heap) {
+        let candidates = std::mem::take(&mut self.candidates);
+
+        for handle in candidates {
+            let info = *self.info(handle);
+            if info.color == Color::Purple && info.count > 0 {
+                self.mark_gray(heap, handle);
+                self.candidates.push(handle);
+                continue;
+            }
+
+            self.info(handle).buffered = false;
+            if info.color == Color::Black && info.count == 0 {
+                heap.free(handle);
+                self.released -= 1;
+            }
+        }
+    }
+
+    /// Grays the subgraph below `handle`, removing the references that come
+    /// from inside it.
+    fn mark_gray(&mut self, heap: &Heap, handle: Handle) {
+        let mut pending = vec![handle];
+
+        while let Some(handle) = pending.pop() {
+            if self.info(handle).color == Color::Gray {
+                continue;
+            }
+            self.info(handle).color = Color::Gray;
+
+            for child in heap.children(handle) {
+                self.info(child).count -= 1;
+                pending.push(child);
+            }
+        }
+    }
+
+    /// Whites gray objects that are only referenced from inside the
+    /// subgraph, and restores everything reachable from the others.
+    fn scan(&mut self, heap: &Heap, handle: Handle) {
+        let mut pending = vec![handle];
+
+        while let Some(handle) = pending.pop() {
+            let info = *self.info(handle);
+            if info.color != Color::Gray {
+                continue;
+            }
+
+            if info.count > 0 {
+                self.scan_black(heap, handle);
+            } else {
+                self.info(handle).color = Color::White;
+                pending.extend(heap.children(handle));
+            }
+        }
+    }
+
+    fn scan_black(&mut self, heap: &Heap, handle: Handle) {
+        self.info(handle).color = Color::Black;
+        let mut pending = vec![handle];
+
+        while let Some(handle) = pending.pop() {
+            for child in heap.children(handle) {
+                let info = self.info(child);
+                info.count += 1;
+                if info.color != Color::Black {
+                    info.color = Color::Black;
+                    pending.push(child);
+                }
+            }
+        }
+    }
+
+    fn collect_roots(&mut self, heap: &mut Heap) {
+        let candidates = std::mem::take(&mut self.candidates);
+        let mut garbage = Vec::new();
+
+        for handle in candidates {
+            self.info(handle).buffered = false;
+
+            let mut pending = vec![handle];
+            while let Some(handle) = pending.pop() {
+                let info = self.info(handle);
+                if info.color != Color::White || info.buffered {
+                    continue;
+                }
+                info.color = Color::Black;
+                pending.extend(heap.children(handle));
+                garbage.push(handle);
+            }
+        }
+
+        for handle in garbage {
+            heap.free(handle);
+        }
+    }
+}
+
+impl Collector for RefCount {
+    fn allocate(&mut self, heap: &mut Heap, object: Object) -> Result<Handle, VmError> {
+        let handle = heap.insert(object)?;
+        if self.info.len() <= handle.index() {
+            self.info.resize(handle.index() + 1, Info::default());
+        }
+        self.info[handle.index()] = Info {
+            generation: handle.generation(),
+            ..Info::default()
+        };
+
+        for child in heap.children(handle) {
+            self.increment(child);
+        }
+        Ok(handle)
+    }
+
+    fn scan_roots(&mut self, _heap: &mut Heap, _roots: &[Value]) -> Result<(), VmError> {
+        // Roots hold counted references, so there is nothing to trace from.
+        Ok(())
+    }
+
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        self.collect_cycles(heap);
+        Ok(())
+    }
+
+    fn is_live(&self, heap: &Heap, handle: Handle) -> bool {
+        heap.contains(handle) && !self.is_released(handle)
+    }
+
+    fn is_idle(&self) -> bool {
+        // Released candidates still refer to the objects they released.
+        self.released == 0
+    }
+
+    fn write_barrier(&mut self, heap: &mut Heap, _obj: Handle, old: Value, new: Value) {
+        if let Some(new) = new.as_object() {
+            self.increment(new);
+        }
+        if let Some(old) = old.as_object() {
+            self.decrement(heap, old);
+        }
+    }
+
+    fn root_pushed(&mut self, _heap: &mut Heap, handle: Handle) {
+        self.increment(handle);
+    }
+
+    fn root_popped(&mut self, heap: &mut Heap, handle: Handle) {
+        self.decrement(heap, handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::collector::collector_tests!(super::RefCount::new());
+
+    use super::RefCount;
+    use crate::collector::test_vm;
+    use crate::error::VmError;
+
+    fn ref_count() -> VM<RefCount> {
+        test_vm(RefCount::new())
+    }
+
+    #[test]
+    fn objects_are_freed_when_their_count_drops_to_zero() {
+        let mut vm = ref_count();
+
+        let one = vm.push_str("1").unwrap();
+        let two = vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+
+        assert_eq!(vm.collector().count(vm.heap(), one), Some(1));
+        assert_eq!(vm.collector().count(vm.heap(), pair), Some(1));
+
+        vm.pop().unwrap();
+
+        assert!(vm.heap().is_empty());
+        assert_eq!(vm.collector().count(vm.heap(), two), None);
+    }
+
+    #[test]
+    fn overwritten_fields_release_their_referent() {
+        let mut vm = ref_count();
+
+        vm.push_str("1").unwrap();
+        let two = vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+        let three = vm.push_str("3").unwrap();
+
+        vm.set_pair_tail(pair, three).unwrap();
+
+        assert!(!vm.heap().contains(two));
+        assert_eq!(vm.collector().count(vm.heap(), three), Some(2));
+    }
+
+    #[test]
+    fn released_candidates_cannot_be_stored_again() {
+        let mut vm = ref_count();
+
+        let one = vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+        vm.push_str("3").unwrap();
+        vm.push_str("4").unwrap();
+        let other = vm.push_pair().unwrap();
+        vm.set_pair_tail(other, pair).unwrap();
+        vm.pop().unwrap();
+        vm.pop().unwrap();
+
+        // The pair was buffered when its count first dropped, so it waits
+        // for the next collection, but its fields were released.
+        assert!(!vm.heap().contains(one));
+        assert_eq!(vm.collector().candidates(), [pair]);
+
+        vm.push_str("5").unwrap();
+        vm.push_str("6").unwrap();
+        let kept = vm.push_pair().unwrap();
+        assert_eq!(vm.set_pair_tail(kept, pair), Err(VmError::InvalidHandle));
+        assert_eq!(vm.verify_heap(), vec![]);
+
+        vm.gc().unwrap();
+        assert_eq!(vm.heap().len(), 3);
+    }
+
+    #[test]
+    fn trial_deletion_frees_garbage_cycles() {
+        let mut vm = ref_count();
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        let a = vm.push_pair().unwrap();
+        vm.push_str("3").unwrap();
+        vm.push_str("4").unwrap();
+        let b = vm.push_pair().unwrap();
+
+        vm.set_pair_tail(a, b).unwrap();
+        vm.set_pair_tail(b, a).unwrap();
+        vm.pop().unwrap();
+        vm.pop().unwrap();
+
+        assert_eq!(vm.heap().len(), 4);
+        assert_eq!(vm.collector().candidates().len(), 2);
+
+        vm.gc().unwrap();
+
+        assert!(vm.heap().is_empty());
+        assert!(vm.collector().candidates().is_empty());
+    }
+
+    #[test]
+    fn trial_deletion_keeps_externally_referenced_cycles() {
+        let mut vm = ref_count();
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        let a = vm.push_pair().unwrap();
+        vm.push_str("3").unwrap();
+        vm.push_str("4").unwrap();
+        let b = vm.push_pair().unwrap();
+
+        vm.set_pair_tail(a, b).unwrap();
+        vm.set_pair_tail(b, a).unwrap();
+        vm.pop().unwrap();
+
+        vm.gc().unwrap();
+
+        assert_eq!(vm.heap().len(), 4);
+        assert_eq!(vm.collector().count(vm.heap(), a), Some(2));
+        assert_eq!(vm.collector().count(vm.heap(), b), Some(1));
+    }
+}
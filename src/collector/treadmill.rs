@@ -0,0 +1,352 @@
+use super::{schedule_steps, Collector, Stepping};
+use crate::error::VmError;
+use crate::heap::{Handle, Heap, Object};
+use crate::stats::GcReason;
+use crate::value::Value;
+
+/// Baker's treadmill: an incremental collector that never moves objects.
+///
+/// The object list is cut into four segments, in order: black, gray, white
+/// and free. Newly allocated objects are linked in at the head, which keeps
+/// them black while a cycle runs. Shading a white object relinks it at the
+/// end of the gray segment, and scanning a gray one just advances the scan
+/// pointer past it, so colors are positions in the list rather than marks
+/// to sweep. Once the gray segment is empty, whatever is still white is
+/// garbage and becomes part of the free segment by moving a single pointer.
+/// Free objects are only released when allocation needs their cell, or
+/// when [`VM::finish_gc`](crate::VM::finish_gc) releases them all. Baker
+/// closes the list into a ring; here it stays open, with the free segment
+/// at the tail where the ring would wrap around to the black head.
+///
+/// Mark bits tell white objects from shaded ones, and the tri-color
+/// invariant is kept the same way as in [`Incremental`](super::Incremental).
+#[derive(Default)]
+pub struct Treadmill {
+    marking: bool,
+    /// First gray object. Everything in front of it is black.
+    scan: Option<Handle>,
+    /// First white object, where the gray segment ends.
+    white: Option<Handle>,
+    /// First free object, where the white segment ends. The free segment
+    /// runs to the end of the list.
+    free: Option<Handle>,
+    white_len: usize,
+    free_len: usize,
+    cycles: usize,
+    /// Completed cycles each object on the list is known to outlive, by
+    /// handle index. Objects in the free segment fell behind: the last
+    /// cycle did not reach them.
+    survives: Vec<usize>,
+}
+
+impl Treadmill {
+    pub fn new() -> Self {
+        Treadmill::default()
+    }
+
+    /// Number of dead objects in the free segment waiting for their cells
+    /// to be reused.
+    pub fn free_len(&self) -> usize {
+        self.free_len
+    }
+
+    pub fn completed_cycles(&self) -> usize {
+        self.cycles
+    }
+
+    fn start_cycle(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        self.marking = true;
+        self.white = heap.first_object();
+        self.scan = self.white;
+        self.white_len = heap.len() - self.free_len;
+        self.shade_roots(heap, roots)
+    }
+
+    fn shade_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        for root in roots.iter().filter_map(Value::as_object) {
+            self.shade(heap, root)?;
+        }
+        Ok(())
+    }
+
+    fn shade(&mut self, heap: &mut Heap, handle: Handle) -> Result<(), VmError> {
+        // Large objects are not on the list; marking them is all it takes.
+        if !heap.mark(handle)? || heap.in_large_space(handle) {
+            return Ok(());
+        }
+
+        let gray_was_empty = self.scan == self.white;
+        let white = self.white.expect("white objects are in the white segment");
+        self.survives[handle.index()] = self.cycles + 1;
+        if white == handle {
+            self.white = heap.get(handle).unwrap().next;
+        } else {
+            heap.move_before(handle, white);
+        }
+
+        if gray_was_empty {
+            self.scan = Some(handle);
+        }
+        self.white_len -= 1;
+        Ok(())
+    }
+
+    /// Blackens the first gray object, if there is one.
+    fn scan_next(&mut self, heap: &mut Heap) -> Result<bool, VmError> {
+        let Some(handle) = self.scan.filter(|&scan| Some(scan) != self.white) else {
+            return Ok(false);
+        };
+
+        self.shade_children(heap, handle)?;
+        self.scan = heap.get(handle).unwrap().next;
+        Ok(true)
+    }
+
+    fn shade_children(&mut self, heap: &mut Heap, handle: Handle) -> Result<(), VmError> {
+        for child in heap.children(handle) {
+            self.shade(heap, child)?;
+        }
+        Ok(())
+    }
+
+    fn finish_cycle(&mut self, heap: &mut Heap) {
+        // Everything still white is garbage: it joins the free segment, and
+        // the black objects turn white for the next cycle.
+        self.free = self.white;
+        self.free_len += self.white_len;
+        self.white_len = 0;
+        // Large objects have no cells worth keeping around for reuse.
+        heap.sweep_large();
+        heap.flip_marks();
+
+        self.marking = false;
+        self.cycles += 1;
+    }
+
+    /// Releases the first free object, returning whether there was one.
+    fn release_free(&mut self, heap: &mut Heap) -> bool {
+        let Some(free) = self.free else {
+            return false;
+        };
+
+        // An empty gray or white segment ends where the free one starts.
+        let next = heap.get(free).unwrap().next;
+        for boundary in [&mut self.scan, &mut self.white, &mut self.free] {
+            if *boundary == Some(free) {
+                *boundary = next;
+            }
+        }
+
+        heap.free(free);
+        self.free_len -= 1;
+        true
+    }
+}
+
+impl Collector for Treadmill {
+    fn allocate(&mut self, heap: &mut Heap, object: Object) -> Result<Handle, VmError> {
+        // The cell of a free object is reused for the new one.
+        self.release_free(heap);
+
+        let handle = heap.insert(object)?;
+        if self.survives.len() <= handle.index() {
+            self.survives.resize(handle.index() + 1, 0);
+        }
+        // Only the next cycle to finish decides about it.
+        self.survives[handle.index()] = self.cycles;
+        if self.marking {
+            self.survives[handle.index()] += 1;
+            // Linked in at the head, so black: whatever it points to has to
+            // be reached as well.
+            heap.mark(handle)?;
+            self.shade_children(heap, handle)?;
+        }
+        Ok(handle)
+    }
+
+    fn schedule(
+        &mut self,
+        heap: &mut Heap,
+        roots: &[Value],
+        due: Option<GcReason>,
+        step_budget: usize,
+    ) -> Result<Option<GcReason>, VmError> {
+        schedule_steps(self, heap, roots, due, step_budget)
+    }
+
+    fn scan_roots(&mut self, heap: &mut Heap, roots: &[Value]) -> Result<(), VmError> {
+        if self.marking {
+            self.shade_roots(heap, roots)
+        } else {
+            self.start_cycle(heap, roots)
+        }
+    }
+
+    fn collect(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        while self.scan_next(heap)? {}
+        self.finish_cycle(heap);
+        Ok(())
+    }
+
+    fn finish(&mut self, heap: &mut Heap) -> Result<(), VmError> {
+        while self.release_free(heap) {}
+        Ok(())
+    }
+
+    fn live_objects(&self, heap: &Heap) -> usize {
+        heap.len() - self.free_len
+    }
+
+    fn is_live(&self, heap: &Heap, handle: Handle) -> bool {
+        // Free objects may already refer to released cells. Large objects
+        // are swept as soon as a cycle ends, so they are live while they
+        // are on the heap.
+        heap.contains(handle)
+            && (heap.in_large_space(handle) || self.survives[handle.index()] >= self.cycles)
+    }
+
+    fn is_idle(&self) -> bool {
+        !self.marking && self.free_len == 0
+    }
+
+    fn write_barrier(&mut self, heap: &mut Heap, _obj: Handle, _old: Value, new: Value) {
+        if let Some(new) = new.as_object().filter(|_| self.marking) {
+            // The VM checked `new` is live before storing it, so shading
+            // cannot fail.
+            let _ = self.shade(heap, new);
+        }
+    }
+}
+
+impl Stepping for Treadmill {
+    fn step(&mut self, heap: &mut Heap, roots: &[Value], budget: usize) -> Result<usize, VmError> {
+        if !self.marking {
+            self.start_cycle(heap, roots)?;
+        }
+
+        let mut scanned = 0;
+        while scanned < budget && self.scan_next(heap)? {
+            scanned += 1;
+        }
+
+        if self.scan == self.white {
+            // Roots pushed since the cycle started are still white.
+            self.shade_roots(heap, roots)?;
+        }
+
+        Ok(scanned)
+    }
+
+    fn is_marking(&self) -> bool {
+        self.marking
+    }
+
+    fn marking_done(&self) -> bool {
+        self.marking && self.scan == self.white
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::collector::collector_tests!(super::Treadmill::new());
+    crate::collector::stepping_tests!(super::Treadmill::new());
+
+    use super::Treadmill;
+    use crate::error::VmError;
+
+    fn run_cycle(vm: &mut VM<Treadmill>) {
+        vm.gc_step(1).unwrap();
+        while vm.collector().is_marking() {
+            vm.gc_step(1).unwrap();
+        }
+    }
+
+    #[test]
+    fn garbage_joins_the_free_segment_without_a_sweep() {
+        let mut vm = test_vm(Treadmill::new());
+
+        let kept: Vec<_> = (0..2)
+            .map(|i| vm.push_str(&i.to_string()).unwrap())
+            .collect();
+        let kept_addresses: Vec<_> = kept.iter().map(|&h| vm.heap().address(h)).collect();
+        let garbage: Vec<_> = (2..4)
+            .map(|i| {
+                let obj = vm.push_str(&i.to_string()).unwrap();
+                vm.heap().address(obj).unwrap()
+            })
+            .collect();
+        vm.pop().unwrap();
+        vm.pop().unwrap();
+
+        let before = dropped_objects();
+        run_cycle(&mut vm);
+
+        assert_eq!(vm.collector().completed_cycles(), 1);
+        assert_eq!(vm.collector().free_len(), 2);
+        assert_eq!(vm.heap().len(), 4);
+        assert_eq!(dropped_objects(), before);
+
+        let obj = vm.push_str("4").unwrap();
+        assert_eq!(dropped_objects() - before, 1);
+        assert!(garbage.contains(&vm.heap().address(obj).unwrap()));
+        assert_eq!(vm.collector().free_len(), 1);
+
+        vm.finish_gc().unwrap();
+        assert_eq!(dropped_objects() - before, 2);
+        assert_eq!(vm.heap().len(), 3);
+
+        let addresses: Vec<_> = kept.iter().map(|&h| vm.heap().address(h)).collect();
+        assert_eq!(addresses, kept_addresses);
+    }
+
+    #[test]
+    fn free_objects_cannot_be_reused() {
+        let mut vm = test_vm(Treadmill::new());
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        let kept = vm.push_pair().unwrap();
+        let garbage = vm.push_str("3").unwrap();
+        vm.pop().unwrap();
+        run_cycle(&mut vm);
+
+        assert_eq!(vm.collector().free_len(), 1);
+        assert_eq!(vm.set_pair_tail(kept, garbage), Err(VmError::InvalidHandle));
+        assert_eq!(vm.push(garbage), Err(VmError::InvalidHandle));
+
+        // Objects allocated between cycles are live until one has finished
+        // without reaching them.
+        let young = vm.push_str("4").unwrap();
+        vm.pop().unwrap();
+        vm.set_pair_tail(kept, young).unwrap();
+        vm.push_str("5").unwrap();
+        let other = vm.pop().unwrap();
+        vm.set_pair_tail(kept, other).unwrap();
+        run_cycle(&mut vm);
+        assert_eq!(vm.set_pair_tail(kept, young), Err(VmError::InvalidHandle));
+
+        vm.finish_gc().unwrap();
+        assert_eq!(vm.heap().len(), 3);
+    }
+
+    #[test]
+    fn reachable_large_objects_stay_live() {
+        let mut vm = VM::with_collector(
+            GcConfig::new()
+                .stack_size(64)
+                .initial_threshold(usize::MAX)
+                .large_object_size(16),
+            Treadmill::new(),
+        );
+
+        let large = vm.push_bytes(&[7; 16]).unwrap();
+        run_cycle(&mut vm);
+        run_cycle(&mut vm);
+
+        assert_eq!(vm.push(large), Ok(()));
+        vm.pop().unwrap();
+        vm.pop().unwrap();
+        run_cycle(&mut vm);
+        assert!(!vm.heap().contains(large));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::heap::LARGE_OBJECT_SIZE;
+
+/// Collection policy for a [`VM`](crate::VM).
+///
+/// After every collection the next threshold is the number of surviving
+/// objects times `growth_factor`, clamped to `min_threshold..=max_threshold`.
+/// Bytes on the heap are limited the same way, starting from and never
+/// dropping below `byte_threshold`. Collectors that mark in steps start a
+/// cycle at either threshold instead of collecting outright.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GcConfig {
+    pub(crate) initial_threshold: usize,
+    pub(crate) growth_factor: f64,
+    pub(crate) min_threshold: usize,
+    pub(crate) max_threshold: usize,
+    pub(crate) max_heap_objects: Option<usize>,
+    pub(crate) byte_threshold: usize,
+    pub(crate) large_object_size: usize,
+    pub(crate) stack_size: usize,
+    pub(crate) step_budget: usize,
+    pub(crate) history_len: usize,
+    pub(crate) logging: bool,
+    pub(crate) verify_heap: bool,
+    pub(crate) intern_strings: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        GcConfig {
+            initial_threshold: 8,
+            growth_factor: 2.0,
+            min_threshold: 8,
+            max_threshold: usize::MAX,
+            max_heap_objects: None,
+            byte_threshold: 1 << 20,
+            large_object_size: LARGE_OBJECT_SIZE,
+            stack_size: 256,
+            step_budget: 16,
+            history_len: 32,
+            logging: false,
+            verify_heap: false,
+            intern_strings: false,
+        }
+    }
+}
+
+impl GcConfig {
+    pub fn new() -> Self {
+        GcConfig::default()
+    }
+
+    /// Number of live objects that triggers the first collection.
+    pub fn initial_threshold(mut self, threshold: usize) -> Self {
+        self.initial_threshold = threshold;
+        self
+    }
+
+    pub fn growth_factor(mut self, factor: f64) -> Self {
+        self.growth_factor = factor;
+        self
+    }
+
+    pub fn min_threshold(mut self, threshold: usize) -> Self {
+        self.min_threshold = threshold;
+        self
+    }
+
+    pub fn max_threshold(mut self, threshold: usize) -> Self {
+        self.max_threshold = threshold;
+        self
+    }
+
+    /// Hard cap on live objects. Allocating past it after a full collection
+    /// fails with [`VmError::OutOfMemory`](crate::VmError::OutOfMemory).
+    pub fn max_heap_objects(mut self, limit: usize) -> Self {
+        self.max_heap_objects = Some(limit);
+        self
+    }
+
+    /// Bytes on the heap, as counted by [`Object::size`](crate::Object::size),
+    /// that trigger the first collection.
+    pub fn byte_threshold(mut self, bytes: usize) -> Self {
+        self.byte_threshold = bytes;
+        self
+    }
+
+    /// Byte buffers at least this long are allocated in the large-object
+    /// space, where they are never moved and are swept separately.
+    pub fn large_object_size(mut self, bytes: usize) -> Self {
+        self.large_object_size = bytes;
+        self
+    }
+
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Objects a collector that marks in steps scans per allocation while a
+    /// cycle is running.
+    pub fn step_budget(mut self, budget: usize) -> Self {
+        self.step_budget = budget;
+        self
+    }
+
+    /// Number of per-collection records the VM keeps; older ones are
+    /// dropped.
+    pub fn history_len(mut self, len: usize) -> Self {
+        self.history_len = len;
+        self
+    }
+
+    /// Reports every collection on stderr.
+    pub fn logging(mut self, enabled: bool) -> Self {
+        self.logging = enabled;
+        self
+    }
+
+    /// Runs [`VM::verify_heap`](crate::VM::verify_heap) before and after
+    /// every collection, panicking if it finds anything wrong.
+    pub fn verify_heap(mut self, enabled: bool) -> Self {
+        self.verify_heap = enabled;
+        self
+    }
+
+    /// Shares one string object between every string the VM creates with
+    /// the same contents, as long as it is reachable.
+    pub fn intern_strings(mut self, enabled: bool) -> Self {
+        self.intern_strings = enabled;
+        self
+    }
+
+    pub(crate) fn next_threshold(&self, live_objects: usize) -> usize {
+        let grown = (live_objects as f64 * self.growth_factor).ceil() as usize;
+        grown.min(self.max_threshold).max(self.min_threshold)
+    }
+
+    pub(crate) fn next_byte_threshold(&self, live_bytes: usize) -> usize {
+        let grown = (live_bytes as f64 * self.growth_factor).ceil() as usize;
+        grown.max(self.byte_threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_threshold_is_clamped() {
+        let config = GcConfig::new()
+            .growth_factor(1.5)
+            .min_threshold(4)
+            .max_threshold(100);
+
+        assert_eq!(config.next_threshold(0), 4);
+        assert_eq!(config.next_threshold(10), 15);
+        assert_eq!(config.next_threshold(7), 11);
+        assert_eq!(config.next_threshold(1000), 100);
+    }
+
+    #[test]
+    fn next_byte_threshold_never_drops_below_the_first() {
+        let config = GcConfig::new().growth_factor(2.0).byte_threshold(100);
+
+        assert_eq!(config.next_byte_threshold(10), 100);
+        assert_eq!(config.next_byte_threshold(80), 160);
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+
+/// Errors reported to the host instead of aborting the process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VmError {
+    StackOverflow,
+    StackUnderflow,
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    OutOfMemory,
+    InvalidHandle,
+    IndexOutOfBounds,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::StackUnderflow => write!(f, "stack underflow"),
+            VmError::TypeMismatch { expected, found } => {
+                write!(f, "type mismatch: expected {expected}, found {found}")
+            }
+            VmError::OutOfMemory => write!(f, "out of memory"),
+            VmError::InvalidHandle => write!(f, "invalid handle"),
+            VmError::IndexOutOfBounds => write!(f, "index out of bounds"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
@@ -0,0 +1,1193 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::error::VmError;
+use crate::map::Map;
+use crate::value::Value;
+
+pub enum ObjectType {
+    Pair(Pair),
+    String(String),
+    Vector(Vec<Value>),
+    Map(Map),
+    Bytes(Vec<u8>),
+}
+
+impl ObjectType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectType::Pair(_) => "pair",
+            ObjectType::String(_) => "string",
+            ObjectType::Vector(_) => "vector",
+            ObjectType::Map(_) => "map",
+            ObjectType::Bytes(_) => "bytes",
+        }
+    }
+}
+
+pub struct Pair {
+    pub head: Value,
+    pub tail: Value,
+}
+
+pub struct Object {
+    pub obj_type: ObjectType,
+    pub prev: Option<Handle>,
+    pub next: Option<Handle>,
+}
+
+impl Object {
+    pub fn new(obj_type: ObjectType) -> Self {
+        Object {
+            obj_type,
+            prev: None,
+            next: None,
+        }
+    }
+
+    /// Bytes the object accounts for on the heap, including the contents
+    /// of a string or byte buffer and the buffers of a vector or map. Changes to the size of an
+    /// object on the heap have to go through [`Heap::modify`].
+    pub fn size(&self) -> usize {
+        let contents = match &self.obj_type {
+            ObjectType::String(s) => s.len(),
+            ObjectType::Bytes(bytes) => bytes.len(),
+            ObjectType::Vector(elements) => elements.capacity() * std::mem::size_of::<Value>(),
+            ObjectType::Map(map) => map.size(),
+            ObjectType::Pair(_) => 0,
+        };
+        std::mem::size_of::<Object>() + contents
+    }
+
+    /// Calls `f` with every handle this object refers to. Immediate values
+    /// in its fields are skipped.
+    pub fn trace(&self, mut f: impl FnMut(Handle)) {
+        match &self.obj_type {
+            ObjectType::String(_) | ObjectType::Bytes(_) => {}
+            ObjectType::Pair(pair) => {
+                for field in [pair.head, pair.tail] {
+                    if let Value::Object(handle) = field {
+                        f(handle);
+                    }
+                }
+            }
+            ObjectType::Vector(elements) => {
+                for element in elements {
+                    if let Value::Object(handle) = *element {
+                        f(handle);
+                    }
+                }
+            }
+            ObjectType::Map(map) => map.trace(|value| {
+                if let Value::Object(handle) = value {
+                    f(handle);
+                }
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static DROPPED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Number of objects destroyed on the current thread, so tests can check
+/// that collected objects are really released.
+#[cfg(test)]
+pub(crate) fn dropped_objects() -> usize {
+    DROPPED.with(|dropped| dropped.get())
+}
+
+#[cfg(test)]
+impl Drop for Object {
+    fn drop(&mut self) {
+        DROPPED.with(|dropped| dropped.set(dropped.get() + 1));
+    }
+}
+
+/// A reference to an object owned by a [`Heap`].
+///
+/// The generation is bumped every time an object is freed, so a handle kept
+/// around after its object was collected no longer resolves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Handle {
+    index: u32,
+    generation: u32,
+}
+
+impl Handle {
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+/// Byte buffers at least this long go to the large-object space unless
+/// the heap is told otherwise.
+pub(crate) const LARGE_OBJECT_SIZE: usize = 8 * 1024;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    generation: u32,
+    place: Option<Place>,
+}
+
+/// Where the object behind an entry is stored.
+#[derive(Clone, Copy)]
+enum Place {
+    /// Address of a cell.
+    Cell(u32),
+    /// Slot in the large-object space.
+    Large(u32),
+}
+
+/// One unit of object storage, indexed by address.
+pub(crate) enum Cell {
+    Empty,
+    Live(Handle, Object),
+    /// Left in from-space by a copying collector once the object moved.
+    Forwarded(u32),
+}
+
+/// Mark bits kept beside the cells, one per address, so marking never
+/// writes to the objects themselves.
+///
+/// Whether a set bit means marked depends on the epoch, and flipping the
+/// epoch unmarks every object at once. That is only right when every live
+/// object is marked at the time, which is what a sweep leaves behind; it is
+/// also why objects are always stored unmarked. The bits are atomic so
+/// several threads can mark through a shared heap.
+#[derive(Default)]
+struct MarkBits {
+    words: Vec<AtomicU64>,
+    /// Stored value of a word whose bits are all unmarked.
+    unmarked: u64,
+}
+
+impl MarkBits {
+    fn bit(addr: usize) -> (usize, u64) {
+        (addr / 64, 1 << (addr % 64))
+    }
+
+    fn get(&self, addr: usize) -> bool {
+        let (word, bit) = MarkBits::bit(addr);
+        self.words
+            .get(word)
+            .is_some_and(|word| (word.load(Ordering::Acquire) ^ self.unmarked) & bit != 0)
+    }
+
+    /// Sets the bit for `addr`, returning whether this call was the one
+    /// that set it.
+    fn mark(&self, addr: usize) -> bool {
+        let (word, bit) = MarkBits::bit(addr);
+        let word = &self.words[word];
+        let old = if self.unmarked == 0 {
+            word.fetch_or(bit, Ordering::AcqRel)
+        } else {
+            word.fetch_and(!bit, Ordering::AcqRel)
+        };
+        (old ^ self.unmarked) & bit == 0
+    }
+
+    fn set(&mut self, addr: usize, marked: bool) {
+        let (word, bit) = MarkBits::bit(addr);
+        if self.words.len() <= word {
+            let unmarked = self.unmarked;
+            self.words
+                .resize_with(word + 1, || AtomicU64::new(unmarked));
+        }
+
+        let word = self.words[word].get_mut();
+        if marked == (self.unmarked == 0) {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+    }
+
+    fn flip(&mut self) {
+        self.unmarked = !self.unmarked;
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.words.truncate(len.div_ceil(64));
+    }
+}
+
+/// Position of a sweep over the object list that can be resumed later.
+pub(crate) struct SweepCursor {
+    next: Option<Handle>,
+}
+
+impl SweepCursor {
+    pub(crate) fn is_done(&self) -> bool {
+        self.next.is_none()
+    }
+}
+
+/// Storage for byte buffers too big to be worth moving.
+///
+/// Its objects never live in a cell, so moving collectors leave them where
+/// they are, and they are not on the object list that sweeps walk. They
+/// hold no references, so nothing has to be traced through them either.
+/// Collectors mark them like any other object, in a bitmap of their own,
+/// and the heap sweeps the space separately whenever a collection is
+/// finished with the cells.
+struct LargeSpace {
+    min_size: usize,
+    slots: Vec<Option<(Handle, Object)>>,
+    free_slots: Vec<u32>,
+    marks: MarkBits,
+    len: usize,
+}
+
+impl Default for LargeSpace {
+    fn default() -> Self {
+        LargeSpace {
+            min_size: LARGE_OBJECT_SIZE,
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            marks: MarkBits::default(),
+            len: 0,
+        }
+    }
+}
+
+impl LargeSpace {
+    fn get(&self, slot: u32) -> Option<&Object> {
+        self.slots
+            .get(slot as usize)?
+            .as_ref()
+            .map(|(_, object)| object)
+    }
+
+    fn get_mut(&mut self, slot: u32) -> Option<&mut Object> {
+        self.slots
+            .get_mut(slot as usize)?
+            .as_mut()
+            .map(|(_, object)| object)
+    }
+}
+
+/// Arena owning every object of a VM.
+///
+/// Handles index a table of entries which in turn point at the address of
+/// the object's cell, so collectors are free to move objects around without
+/// invalidating the handles held by the mutator or stored in pairs. Live
+/// objects are also chained through `Object::next` and `Object::prev` so
+/// collectors can walk them without scanning empty cells and unlink any of
+/// them in constant time. Mark bits live in a bitmap next to the cells.
+/// Big byte buffers are kept apart, in a large-object space.
+///
+/// While the VM has observers, objects the collector frees are kept aside
+/// instead of dropped, so they can be reported once it is done.
+#[derive(Default)]
+pub struct Heap {
+    entries: Vec<Entry>,
+    free_entries: Vec<u32>,
+    cells: Vec<Cell>,
+    free_cells: Vec<u32>,
+    marks: MarkBits,
+    len: usize,
+    bytes: usize,
+    first_object: Option<Handle>,
+    large: LargeSpace,
+    freed: Option<Vec<(Handle, Object)>>,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap::default()
+    }
+
+    /// A heap that puts byte buffers of at least `min_size` bytes in the
+    /// large-object space.
+    pub fn with_large_object_size(min_size: usize) -> Self {
+        let mut heap = Heap::default();
+        heap.large.min_size = min_size;
+        heap
+    }
+
+    /// Whether `object` belongs in the large-object space, where
+    /// [`Heap::insert`] puts it.
+    pub(crate) fn is_large(&self, object: &Object) -> bool {
+        matches!(&object.obj_type, ObjectType::Bytes(bytes) if bytes.len() >= self.large.min_size)
+    }
+
+    /// Stores `object` in the first free cell, or at the end of the heap if
+    /// there is none, and links it at the head of the object list. Large
+    /// objects go to the large-object space instead.
+    pub fn insert(&mut self, object: Object) -> Result<Handle, VmError> {
+        if self.is_large(&object) {
+            return self.insert_large(object);
+        }
+
+        let addr = match self.free_cells.pop() {
+            Some(addr) => addr,
+            None => {
+                let addr = u32::try_from(self.cells.len()).map_err(|_| VmError::OutOfMemory)?;
+                self.cells.push(Cell::Empty);
+                addr
+            }
+        };
+        self.place(addr, object)
+    }
+
+    /// Stores `object` in the cell at `addr`, which must be empty, growing
+    /// the heap if it ends before `addr`. For collectors that keep track of
+    /// free space themselves; see [`Heap::forget_free_cells`]. Large
+    /// objects have to go through [`Heap::insert`].
+    pub(crate) fn insert_at(&mut self, addr: usize, object: Object) -> Result<Handle, VmError> {
+        debug_assert!(!self.is_large(&object));
+        let addr = u32::try_from(addr).map_err(|_| VmError::OutOfMemory)?;
+        if self.cells.len() <= addr as usize {
+            self.cells.resize_with(addr as usize + 1, || Cell::Empty);
+        }
+        debug_assert!(matches!(self.cells[addr as usize], Cell::Empty));
+        self.place(addr, object)
+    }
+
+    fn place(&mut self, addr: u32, mut object: Object) -> Result<Handle, VmError> {
+        object.prev = None;
+        object.next = self.first_object;
+
+        let handle = self.new_entry(Place::Cell(addr))?;
+        if let Some(first) = self.first_object {
+            self.get_mut(first).unwrap().prev = Some(handle);
+        }
+
+        let size = object.size();
+        self.cells[addr as usize] = Cell::Live(handle, object);
+        self.marks.set(addr as usize, false);
+        self.len += 1;
+        self.bytes += size;
+        self.first_object = Some(handle);
+        Ok(handle)
+    }
+
+    /// Stores `object` in a free slot of the large-object space, off the
+    /// object list.
+    fn insert_large(&mut self, mut object: Object) -> Result<Handle, VmError> {
+        object.prev = None;
+        object.next = None;
+
+        let slot = match self.large.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                let slot =
+                    u32::try_from(self.large.slots.len()).map_err(|_| VmError::OutOfMemory)?;
+                self.large.slots.push(None);
+                slot
+            }
+        };
+
+        let handle = self.new_entry(Place::Large(slot))?;
+        self.bytes += object.size();
+        self.large.slots[slot as usize] = Some((handle, object));
+        self.large.marks.set(slot as usize, false);
+        self.large.len += 1;
+        Ok(handle)
+    }
+
+    fn new_entry(&mut self, place: Place) -> Result<Handle, VmError> {
+        match self.free_entries.pop() {
+            Some(index) => {
+                let entry = &mut self.entries[index as usize];
+                entry.place = Some(place);
+                Ok(Handle {
+                    index,
+                    generation: entry.generation,
+                })
+            }
+            None => {
+                let index = u32::try_from(self.entries.len()).map_err(|_| VmError::OutOfMemory)?;
+                self.entries.push(Entry {
+                    generation: 0,
+                    place: Some(place),
+                });
+                Ok(Handle {
+                    index,
+                    generation: 0,
+                })
+            }
+        }
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&Object> {
+        match self.locate(handle)? {
+            Place::Cell(addr) => self.object_at(addr as usize),
+            Place::Large(slot) => self.large.get(slot),
+        }
+    }
+
+    /// Handles the object at `handle` refers to, or none if there is no
+    /// such object.
+    pub fn children(&self, handle: Handle) -> Vec<Handle> {
+        let mut children = Vec::new();
+        if let Some(obj) = self.get(handle) {
+            obj.trace(|child| children.push(child));
+        }
+        children
+    }
+
+    pub fn get_mut(&mut self, handle: Handle) -> Option<&mut Object> {
+        match self.locate(handle)? {
+            Place::Cell(addr) => self.object_at_mut(addr as usize),
+            Place::Large(slot) => self.large.get_mut(slot),
+        }
+    }
+
+    /// Runs `f` on the object and accounts for whatever it changed about
+    /// the object's size.
+    pub fn modify<R>(&mut self, handle: Handle, f: impl FnOnce(&mut Object) -> R) -> Option<R> {
+        let object = self.get_mut(handle)?;
+        let before = object.size();
+        let result = f(object);
+        let after = object.size();
+        self.bytes = self.bytes - before + after;
+        Some(result)
+    }
+
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.get(handle).is_some()
+    }
+
+    /// Where the object currently lives. Moving collectors change this.
+    /// Objects in the large-object space have no address.
+    pub fn address(&self, handle: Handle) -> Option<usize> {
+        self.addr(handle).map(|addr| addr as usize)
+    }
+
+    /// Number of cells the heap spans, free ones included.
+    pub fn extent(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn first_object(&self) -> Option<Handle> {
+        self.first_object
+    }
+
+    pub fn in_large_space(&self, handle: Handle) -> bool {
+        matches!(self.locate(handle), Some(Place::Large(_)))
+    }
+
+    /// Walks the large-object space.
+    pub fn large_objects(&self) -> impl Iterator<Item = (Handle, &Object)> + '_ {
+        self.large
+            .slots
+            .iter()
+            .flatten()
+            .map(|(handle, object)| (*handle, object))
+    }
+
+    /// Number of objects in the large-object space, which
+    /// [`Heap::len`] leaves out.
+    pub fn large_len(&self) -> usize {
+        self.large.len
+    }
+
+    /// Walks the object list.
+    pub fn iter(&self) -> impl Iterator<Item = (Handle, &Object)> + '_ {
+        let mut next = self.first_object;
+        std::iter::from_fn(move || {
+            let handle = next?;
+            let object = self.get(handle)?;
+            next = object.next;
+            Some((handle, object))
+        })
+    }
+
+    /// Walks the object list and frees every object for which `keep`
+    /// returns false, splicing it out of the list. Returns how many objects
+    /// were freed.
+    pub fn retain(&mut self, keep: impl FnMut(Handle, &mut Object) -> bool) -> usize {
+        self.retain_until(None, keep)
+    }
+
+    /// Like [`Heap::retain`], but stops walking the object list when it
+    /// reaches `end`, leaving `end` and everything after it untouched.
+    pub fn retain_until(
+        &mut self,
+        end: Option<Handle>,
+        mut keep: impl FnMut(Handle, &mut Object) -> bool,
+    ) -> usize {
+        let mut cursor = self.sweep_cursor();
+        self.sweep_list(&mut cursor, end, usize::MAX, |handle, object, _| {
+            keep(handle, object)
+        })
+    }
+
+    /// Frees every unmarked object, then unmarks the survivors by flipping
+    /// the mark epoch. Returns how many objects were freed from the object
+    /// list; the large-object space is swept as well.
+    pub(crate) fn sweep(&mut self) -> usize {
+        let mut cursor = self.sweep_cursor();
+        self.sweep_step(&mut cursor, usize::MAX)
+    }
+
+    /// Resumes a sweep of unmarked objects at `cursor`, visiting at most
+    /// `budget` objects, and flips the mark epoch once the cursor is done.
+    /// Objects allocated since the cursor was created are not visited, so
+    /// they have to be marked to survive the flip unmarked. The large-object
+    /// space is swept in one go at the end and not counted.
+    pub(crate) fn sweep_step(&mut self, cursor: &mut SweepCursor, budget: usize) -> usize {
+        let freed = self.sweep_list(cursor, None, budget, |_, _, marked| marked);
+        if cursor.is_done() {
+            self.sweep_large();
+            self.marks.flip();
+        }
+        freed
+    }
+
+    /// Frees every unmarked object in the large-object space and unmarks
+    /// the others by flipping the space's own mark epoch. Returns how many
+    /// objects were freed.
+    pub(crate) fn sweep_large(&mut self) -> usize {
+        let garbage: Vec<_> = self
+            .large_objects()
+            .map(|(handle, _)| handle)
+            .filter(|&handle| !self.is_marked(handle))
+            .collect();
+        for &handle in &garbage {
+            self.free(handle);
+        }
+        self.large.marks.flip();
+        garbage.len()
+    }
+
+    /// Frees every unmarked object in front of `end` and unmarks the others
+    /// one by one, calling `survived` with each. Used where objects past
+    /// `end` are not marked, so the epoch cannot be flipped.
+    pub(crate) fn sweep_until(
+        &mut self,
+        end: Option<Handle>,
+        mut survived: impl FnMut(Handle),
+    ) -> usize {
+        let mut cursor = self.sweep_cursor();
+        let mut survivors = Vec::new();
+        let freed = self.sweep_list(&mut cursor, end, usize::MAX, |handle, _, marked| {
+            if marked {
+                survivors.push(handle);
+            }
+            marked
+        });
+
+        for handle in survivors {
+            let addr = self.addr(handle).unwrap() as usize;
+            self.marks.set(addr, false);
+            survived(handle);
+        }
+        freed
+    }
+
+    /// A sweep position at the head of the object list, to be advanced with
+    /// [`Heap::sweep_step`].
+    pub(crate) fn sweep_cursor(&self) -> SweepCursor {
+        SweepCursor {
+            next: self.first_object,
+        }
+    }
+
+    fn sweep_list(
+        &mut self,
+        cursor: &mut SweepCursor,
+        end: Option<Handle>,
+        budget: usize,
+        mut keep: impl FnMut(Handle, &mut Object, bool) -> bool,
+    ) -> usize {
+        let mut freed = 0;
+        let mut visited = 0;
+
+        while let Some(handle) = cursor.next.filter(|&handle| Some(handle) != end) {
+            if visited == budget {
+                break;
+            }
+            visited += 1;
+
+            let addr = self.addr(handle).unwrap() as usize;
+            let marked = self.marks.get(addr);
+            let o = self
+                .object_at_mut(addr)
+                .expect("object list should only link live objects");
+
+            cursor.next = o.next;
+
+            if !keep(handle, o, marked) {
+                self.free(handle);
+                freed += 1;
+            }
+        }
+
+        freed
+    }
+
+    /// Unlinks the object from the object list and takes it off the heap,
+    /// invalidating every handle to it.
+    pub fn remove(&mut self, handle: Handle) -> Option<Object> {
+        let addr = match self.locate(handle)? {
+            Place::Cell(addr) => addr,
+            Place::Large(slot) => {
+                let (_, object) = self.large.slots[slot as usize].take()?;
+                self.large.free_slots.push(slot);
+                self.large.len -= 1;
+                self.free_entry(handle.index, object.size());
+                return Some(object);
+            }
+        };
+        self.get(handle)?;
+        self.unlink(handle);
+
+        let Cell::Live(_, object) = std::mem::replace(&mut self.cells[addr as usize], Cell::Empty)
+        else {
+            unreachable!("the object was just looked up");
+        };
+
+        self.free_cells.push(addr);
+        self.len -= 1;
+        self.free_entry(handle.index, object.size());
+        Some(object)
+    }
+
+    /// Frees the object as garbage, returning whether there was one.
+    pub(crate) fn free(&mut self, handle: Handle) -> bool {
+        match self.remove(handle) {
+            Some(object) => {
+                self.discard(handle, object);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops a freed object, or keeps it for [`Heap::take_freed`].
+    fn discard(&mut self, handle: Handle, object: Object) {
+        if let Some(freed) = &mut self.freed {
+            freed.push((handle, object));
+        }
+    }
+
+    /// Starts keeping the objects collectors free until they are taken.
+    pub(crate) fn keep_freed(&mut self) {
+        self.freed.get_or_insert_with(Vec::new);
+    }
+
+    /// The objects freed since the last call, with the handles they had,
+    /// if [`Heap::keep_freed`] was called.
+    pub(crate) fn take_freed(&mut self) -> Vec<(Handle, Object)> {
+        self.freed.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Moves the object to just before `before` in the object list.
+    pub(crate) fn move_before(&mut self, handle: Handle, before: Handle) {
+        if handle == before {
+            return;
+        }
+        self.unlink(handle);
+
+        let prev = self.get(before).unwrap().prev;
+        let object = self.get_mut(handle).unwrap();
+        object.prev = prev;
+        object.next = Some(before);
+
+        self.get_mut(before).unwrap().prev = Some(handle);
+        match prev {
+            Some(prev) => self.get_mut(prev).unwrap().next = Some(handle),
+            None => self.first_object = Some(handle),
+        }
+    }
+
+    fn unlink(&mut self, handle: Handle) {
+        let object = self.get_mut(handle).unwrap();
+        let prev = object.prev.take();
+        let next = object.next.take();
+
+        match prev {
+            Some(prev) => self.get_mut(prev).unwrap().next = next,
+            None => self.first_object = next,
+        }
+        if let Some(next) = next {
+            self.get_mut(next).unwrap().prev = prev;
+        }
+    }
+
+    /// Number of objects on the object list. Objects in the large-object
+    /// space are counted by [`Heap::large_len`].
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Total size of the objects on the heap, as counted by
+    /// [`Object::size`].
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the current or last collection marked the object.
+    pub fn is_marked(&self, handle: Handle) -> bool {
+        match self.locate(handle) {
+            Some(Place::Cell(addr)) => self.is_marked_at(addr as usize),
+            Some(Place::Large(slot)) => self.large.marks.get(slot as usize),
+            None => false,
+        }
+    }
+
+    /// Unmarks every marked object and marks every unmarked one in constant
+    /// time. Only meant for when no unmarked object is going to be looked at
+    /// again; [`Heap::sweep`] frees them first.
+    pub(crate) fn flip_marks(&mut self) {
+        self.marks.flip();
+    }
+
+    pub(crate) fn is_marked_at(&self, addr: usize) -> bool {
+        self.marks.get(addr)
+    }
+
+    /// Marks the object, returning whether it was unmarked before.
+    pub(crate) fn mark(&mut self, handle: Handle) -> Result<bool, VmError> {
+        self.mark_shared(handle)
+    }
+
+    /// Like [`Heap::mark`], but through a shared reference so several
+    /// threads can mark at once.
+    pub(crate) fn mark_shared(&self, handle: Handle) -> Result<bool, VmError> {
+        match self.locate(handle) {
+            Some(Place::Cell(addr)) if self.object_at(addr as usize).is_some() => {
+                Ok(self.marks.mark(addr as usize))
+            }
+            Some(Place::Large(slot)) => Ok(self.large.marks.mark(slot as usize)),
+            _ => Err(VmError::InvalidHandle),
+        }
+    }
+
+    fn locate(&self, handle: Handle) -> Option<Place> {
+        self.entries
+            .get(handle.index as usize)
+            .filter(|entry| entry.generation == handle.generation)
+            .and_then(|entry| entry.place)
+    }
+
+    fn addr(&self, handle: Handle) -> Option<u32> {
+        match self.locate(handle)? {
+            Place::Cell(addr) => Some(addr),
+            Place::Large(_) => None,
+        }
+    }
+
+    /// Invalidates every handle to the entry and stops counting the `size`
+    /// bytes of its object. The caller is responsible for where the object
+    /// was stored and for having unlinked it from the object list.
+    fn free_entry(&mut self, index: u32, size: usize) {
+        let entry = &mut self.entries[index as usize];
+        entry.place = None;
+        entry.generation = entry.generation.wrapping_add(1);
+        self.free_entries.push(index);
+        self.bytes -= size;
+    }
+
+    pub(crate) fn object_at(&self, addr: usize) -> Option<&Object> {
+        match self.cells.get(addr)? {
+            Cell::Live(_, object) => Some(object),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn object_at_mut(&mut self, addr: usize) -> Option<&mut Object> {
+        match self.cells.get_mut(addr)? {
+            Cell::Live(_, object) => Some(object),
+            _ => None,
+        }
+    }
+
+    /// Points the entry of the object at `addr` to `new_addr` ahead of the
+    /// object actually being moved there by [`Heap::slide`].
+    pub(crate) fn forward(&mut self, addr: usize, new_addr: usize) {
+        if let Cell::Live(handle, _) = &self.cells[addr] {
+            self.entries[handle.index as usize].place = Some(Place::Cell(new_addr as u32));
+        }
+    }
+
+    /// Moves the cell at `addr` down to `new_addr`, which must be empty or
+    /// already vacated.
+    pub(crate) fn slide(&mut self, addr: usize, new_addr: usize) {
+        debug_assert!(new_addr <= addr);
+        if addr != new_addr {
+            self.cells[new_addr] = std::mem::replace(&mut self.cells[addr], Cell::Empty);
+            let marked = self.marks.get(addr);
+            self.marks.set(new_addr, marked);
+        }
+    }
+
+    /// Moves the object at `addr` to the empty cell at `new_addr`, growing
+    /// the heap if needed, and points its entry there.
+    pub(crate) fn relocate(&mut self, addr: usize, new_addr: usize) {
+        if self.cells.len() <= new_addr {
+            self.cells.resize_with(new_addr + 1, || Cell::Empty);
+        }
+        debug_assert!(matches!(self.cells[new_addr], Cell::Empty));
+
+        self.forward(addr, new_addr);
+        self.cells[new_addr] = std::mem::replace(&mut self.cells[addr], Cell::Empty);
+        let marked = self.marks.get(addr);
+        self.marks.set(new_addr, marked);
+    }
+
+    /// Drops the list of cells freed since the last call. Collectors placing
+    /// objects with [`Heap::insert_at`] call this after collecting, since
+    /// nothing else would ever take cells off it.
+    pub(crate) fn forget_free_cells(&mut self) {
+        self.free_cells.clear();
+    }
+
+    /// Frees the object at `addr` without touching the object list; the
+    /// caller has to rebuild it with [`Heap::finish_compaction`].
+    pub(crate) fn free_at(&mut self, addr: usize) {
+        if let Cell::Live(handle, object) = std::mem::replace(&mut self.cells[addr], Cell::Empty) {
+            self.len -= 1;
+            self.free_entry(handle.index, object.size());
+            self.discard(handle, object);
+        }
+    }
+
+    /// Drops the now empty cells past `extent` so the next allocation bumps
+    /// right after the last survivor, sweeps the large-object space and
+    /// relinks the object list in address order. Every object left is a
+    /// marked survivor, so the marks are cleared by flipping the epoch.
+    pub(crate) fn finish_compaction(&mut self, extent: usize) {
+        debug_assert!(self.cells[extent..]
+            .iter()
+            .all(|cell| matches!(cell, Cell::Empty)));
+        self.cells.truncate(extent);
+        self.marks.truncate(extent);
+        self.free_cells.clear();
+        self.sweep_large();
+        self.marks.flip();
+        self.relink();
+    }
+
+    /// Swaps the heap's storage for `to_space`, which must be empty, and
+    /// returns the old storage so a copying collector can evacuate it.
+    pub(crate) fn begin_evacuation(&mut self, mut to_space: Vec<Cell>) -> Vec<Cell> {
+        debug_assert!(to_space.is_empty());
+        std::mem::swap(&mut self.cells, &mut to_space);
+        self.free_cells.clear();
+        to_space
+    }
+
+    /// Copies the object behind `handle` from `from_space` to the end of the
+    /// heap unless it was copied already, leaving a forwarding address behind.
+    /// Returns the object's to-space address, or `None` for an object in the
+    /// large-object space, which is marked instead of copied.
+    ///
+    /// Entries keep their from-space address until
+    /// [`Heap::finish_evacuation`], which is what lets this tell a forwarded
+    /// object from one that still has to move.
+    pub(crate) fn evacuate(
+        &mut self,
+        from_space: &mut [Cell],
+        handle: Handle,
+    ) -> Result<Option<usize>, VmError> {
+        let addr = match self.locate(handle).ok_or(VmError::InvalidHandle)? {
+            Place::Cell(addr) => addr as usize,
+            Place::Large(_) => {
+                self.mark(handle)?;
+                return Ok(None);
+            }
+        };
+        let cell = from_space.get_mut(addr).ok_or(VmError::InvalidHandle)?;
+
+        if let Cell::Forwarded(new_addr) = cell {
+            return Ok(Some(*new_addr as usize));
+        }
+
+        let new_addr = u32::try_from(self.cells.len()).map_err(|_| VmError::OutOfMemory)?;
+        match std::mem::replace(cell, Cell::Forwarded(new_addr)) {
+            Cell::Live(handle, object) => {
+                self.cells.push(Cell::Live(handle, object));
+                self.marks.set(new_addr as usize, false);
+                Ok(Some(new_addr as usize))
+            }
+            _ => Err(VmError::InvalidHandle),
+        }
+    }
+
+    /// Points every entry at its object's new cell, frees whatever was left
+    /// behind in `from_space`, sweeps the large-object space and relinks the
+    /// object list in address order. Returns the emptied from-space so its
+    /// allocation can be reused.
+    pub(crate) fn finish_evacuation(&mut self, mut from_space: Vec<Cell>) -> Vec<Cell> {
+        for cell in from_space.drain(..) {
+            if let Cell::Live(handle, object) = cell {
+                self.len -= 1;
+                self.free_entry(handle.index, object.size());
+                self.discard(handle, object);
+            }
+        }
+        self.sweep_large();
+
+        self.relink();
+        from_space
+    }
+
+    /// Rebuilds the entry addresses and the object list from the cells, in
+    /// address order.
+    fn relink(&mut self) {
+        let mut next = None;
+        for (addr, cell) in self.cells.iter_mut().enumerate().rev() {
+            if let Cell::Live(handle, object) = cell {
+                self.entries[handle.index as usize].place = Some(Place::Cell(addr as u32));
+                object.next = next;
+                next = Some(*handle);
+            }
+        }
+        self.first_object = next;
+
+        let mut prev = None;
+        for cell in self.cells.iter_mut() {
+            if let Cell::Live(handle, object) = cell {
+                object.prev = prev;
+                prev = Some(*handle);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pair holding `value`, as the smallest object that carries one.
+    fn boxed(value: i64) -> Object {
+        Object::new(ObjectType::Pair(Pair {
+            head: Value::Int(value),
+            tail: Value::Nil,
+        }))
+    }
+
+    fn unboxed(object: &Object) -> i64 {
+        match object.obj_type {
+            ObjectType::Pair(Pair {
+                head: Value::Int(value),
+                ..
+            }) => value,
+            _ => panic!("should be a boxed int"),
+        }
+    }
+
+    #[test]
+    fn stale_handles_do_not_resolve() {
+        let mut heap = Heap::new();
+
+        let a = heap.insert(boxed(1)).unwrap();
+        assert_eq!(heap.retain(|_, _| false), 1);
+
+        let b = heap.insert(boxed(2)).unwrap();
+
+        assert_eq!(a.index(), b.index());
+        assert!(heap.get(a).is_none());
+        assert_eq!(unboxed(heap.get(b).unwrap()), 2);
+        assert_eq!(heap.len(), 1);
+    }
+
+    #[test]
+    fn sweep_step_resumes_behind_new_objects() {
+        let mut heap = Heap::new();
+
+        let old: Vec<_> = (0..4).map(|i| heap.insert(boxed(i)).unwrap()).collect();
+        heap.mark(old[1]).unwrap();
+        heap.mark(old[3]).unwrap();
+        let mut cursor = heap.sweep_cursor();
+
+        let new = heap.insert(boxed(4)).unwrap();
+        heap.mark(new).unwrap();
+
+        assert_eq!(heap.sweep_step(&mut cursor, 1), 0);
+        assert_eq!(heap.sweep_step(&mut cursor, 1), 1);
+        assert_eq!(heap.sweep_step(&mut cursor, 10), 1);
+        assert!(cursor.is_done());
+        assert!(!heap.is_marked(new));
+
+        let live: Vec<_> = heap.iter().map(|(handle, _)| handle).collect();
+        assert_eq!(live, vec![new, old[3], old[1]]);
+    }
+
+    #[test]
+    fn remove_unlinks_in_both_directions() {
+        let mut heap = Heap::new();
+
+        let handles: Vec<_> = (0..3).map(|i| heap.insert(boxed(i)).unwrap()).collect();
+
+        assert!(heap.remove(handles[1]).is_some());
+        assert!(heap.remove(handles[1]).is_none());
+
+        assert_eq!(heap.get(handles[2]).unwrap().next, Some(handles[0]));
+        assert_eq!(heap.get(handles[0]).unwrap().prev, Some(handles[2]));
+
+        assert!(heap.remove(handles[2]).is_some());
+        assert_eq!(heap.first_object(), Some(handles[0]));
+        assert_eq!(heap.get(handles[0]).unwrap().prev, None);
+    }
+
+    #[test]
+    fn retain_keeps_the_list_linked() {
+        let mut heap = Heap::new();
+
+        let handles: Vec<_> = (0..5).map(|i| heap.insert(boxed(i)).unwrap()).collect();
+
+        let freed = heap.retain(|_, o| unboxed(o) % 2 == 0);
+
+        let live: Vec<_> = heap.iter().map(|(handle, _)| handle).collect();
+        assert_eq!(freed, 2);
+        assert_eq!(live, vec![handles[4], handles[2], handles[0]]);
+        assert_eq!(heap.first_object(), Some(handles[4]));
+    }
+
+    #[test]
+    fn mark_bits_are_set_once() {
+        let mut heap = Heap::new();
+
+        let handles: Vec<_> = (0..130).map(|i| heap.insert(boxed(i)).unwrap()).collect();
+
+        assert!(heap.mark(handles[3]).unwrap());
+        assert!(!heap.mark(handles[3]).unwrap());
+        assert!(heap.mark_shared(handles[129]).unwrap());
+
+        let marked: Vec<_> = (0..heap.extent())
+            .filter(|&a| heap.is_marked_at(a))
+            .collect();
+        assert_eq!(marked, vec![3, 129]);
+    }
+
+    #[test]
+    fn sweeping_flips_the_mark_epoch() {
+        let mut heap = Heap::new();
+
+        let handles: Vec<_> = (0..4).map(|i| heap.insert(boxed(i)).unwrap()).collect();
+        heap.mark(handles[0]).unwrap();
+        heap.mark(handles[2]).unwrap();
+
+        assert_eq!(heap.sweep(), 2);
+        assert!(!heap.is_marked(handles[0]));
+        assert!(!heap.is_marked(handles[2]));
+
+        // Reused cells start unmarked in the new epoch too.
+        let new = heap.insert(boxed(4)).unwrap();
+        assert!(!heap.is_marked(new));
+        assert!(heap.mark(new).unwrap());
+        assert_eq!(heap.sweep(), 2);
+        assert_eq!(heap.len(), 1);
+    }
+
+    #[test]
+    fn move_before_relinks_the_object() {
+        let mut heap = Heap::new();
+
+        let handles: Vec<_> = (0..4).map(|i| heap.insert(boxed(i)).unwrap()).collect();
+
+        heap.move_before(handles[0], handles[2]);
+        heap.move_before(handles[1], handles[3]);
+
+        let live: Vec<_> = heap.iter().map(|(handle, _)| handle).collect();
+        assert_eq!(live, vec![handles[1], handles[3], handles[0], handles[2]]);
+        assert_eq!(heap.get(handles[1]).unwrap().prev, None);
+        assert_eq!(heap.get(handles[2]).unwrap().prev, Some(handles[0]));
+        assert_eq!(heap.get(handles[2]).unwrap().next, None);
+    }
+
+    #[test]
+    fn bytes_follow_the_objects_on_the_heap() {
+        let mut heap = Heap::new();
+        let size = boxed(0).size();
+
+        let handles: Vec<_> = (0..3).map(|i| heap.insert(boxed(i)).unwrap()).collect();
+        assert_eq!(heap.bytes(), 3 * size);
+
+        heap.remove(handles[1]);
+        assert_eq!(heap.bytes(), 2 * size);
+
+        heap.retain(|_, _| false);
+        assert_eq!(heap.bytes(), 0);
+    }
+
+    #[test]
+    fn modify_accounts_for_growing_objects() {
+        let mut heap = Heap::new();
+
+        let vector = heap
+            .insert(Object::new(ObjectType::Vector(Vec::new())))
+            .unwrap();
+        let empty = heap.bytes();
+
+        heap.modify(vector, |object| match &mut object.obj_type {
+            ObjectType::Vector(elements) => elements.reserve_exact(4),
+            _ => unreachable!(),
+        });
+        assert_eq!(heap.bytes(), empty + 4 * std::mem::size_of::<Value>());
+
+        heap.remove(vector);
+        assert_eq!(heap.bytes(), 0);
+    }
+
+    #[test]
+    fn large_objects_stay_off_the_object_list() {
+        let mut heap = Heap::with_large_object_size(4);
+        let bytes = |len| Object::new(ObjectType::Bytes(vec![0; len]));
+
+        let small = heap.insert(bytes(3)).unwrap();
+        let a = heap.insert(bytes(4)).unwrap();
+        let b = heap.insert(bytes(5)).unwrap();
+
+        assert!(!heap.in_large_space(small));
+        assert!(heap.in_large_space(a));
+        assert_eq!(heap.address(a), None);
+        assert_eq!(heap.len(), 1);
+        assert_eq!(heap.large_len(), 2);
+        assert_eq!(heap.iter().count(), 1);
+        assert_eq!(
+            heap.bytes(),
+            bytes(3).size() + bytes(4).size() + bytes(5).size()
+        );
+
+        assert!(heap.mark(b).unwrap());
+        assert_eq!(heap.sweep_large(), 1);
+        assert!(!heap.contains(a));
+        assert!(!heap.is_marked(b));
+        assert!(heap.contains(small));
+
+        let c = heap.insert(bytes(6)).unwrap();
+        assert_eq!(heap.large_len(), 2);
+        assert_eq!(heap.sweep(), 1);
+        assert!(heap.is_empty());
+        assert_eq!(heap.large_len(), 0);
+        assert!(!heap.contains(c));
+    }
+
+    #[test]
+    fn freed_objects_are_kept_until_taken() {
+        let mut heap = Heap::new();
+
+        let a = heap.insert(boxed(1)).unwrap();
+        heap.keep_freed();
+        let b = heap.insert(boxed(2)).unwrap();
+        heap.mark(a).unwrap();
+
+        let before = dropped_objects();
+        assert_eq!(heap.sweep(), 1);
+        assert_eq!(dropped_objects(), before);
+
+        let freed = heap.take_freed();
+        assert_eq!(freed.len(), 1);
+        assert_eq!(freed[0].0, b);
+        assert_eq!(unboxed(&freed[0].1), 2);
+        assert!(heap.take_freed().is_empty());
+
+        drop(freed);
+        assert_eq!(dropped_objects() - before, 1);
+    }
+}
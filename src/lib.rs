@@ -0,0 +1,24 @@
+mod collector;
+mod config;
+mod error;
+mod heap;
+mod map;
+mod observer;
+mod stats;
+mod value;
+mod verify;
+mod vm;
+
+pub use collector::{
+    Collector, Concurrent, Generational, Immix, Incremental, MarkCompact, MarkSweep, RefCount,
+    SemiSpace, Stepping, Treadmill,
+};
+pub use config::GcConfig;
+pub use error::VmError;
+pub use heap::{Handle, Heap, Object, ObjectType, Pair};
+pub use map::{Map, MapMode};
+pub use observer::GcObserver;
+pub use stats::{GcReason, GcRecord, GcStats};
+pub use value::Value;
+pub use verify::Violation;
+pub use vm::VM;
@@ -0,0 +1,302 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use crate::error::VmError;
+use crate::heap::{Heap, ObjectType};
+use crate::value::Value;
+
+/// Pairs nested deeper than this inside a key do not contribute to its
+/// structural hash, which keeps hashing cyclic pairs finite.
+const MAX_HASH_DEPTH: usize = 8;
+
+/// How a [`Map`] decides whether two keys are the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapMode {
+    /// Objects are the same key only if they are the same object. They are
+    /// hashed by handle, which stays the same when a collector moves the
+    /// object.
+    Identity,
+    /// Strings with the same contents and pairs with structurally equal
+    /// fields are the same key, even if they are different objects. Other
+    /// objects are still compared by identity.
+    Structural,
+}
+
+impl MapMode {
+    pub(crate) fn hash(self, heap: &Heap, key: Value) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash_value(heap, key, 0, &mut hasher);
+        hasher.finish()
+    }
+
+    fn hash_value(self, heap: &Heap, value: Value, depth: usize, hasher: &mut DefaultHasher) {
+        let handle = match value {
+            Value::Nil => return 0u8.hash(hasher),
+            Value::Bool(b) => return (1u8, b).hash(hasher),
+            Value::Int(i) => return (2u8, i).hash(hasher),
+            Value::Float(f) => return (3u8, f.to_bits()).hash(hasher),
+            Value::Object(handle) => handle,
+        };
+
+        let obj_type = heap.get(handle).map(|object| &object.obj_type);
+        match (self, obj_type) {
+            (MapMode::Structural, Some(ObjectType::String(s))) => (5u8, s).hash(hasher),
+            (MapMode::Structural, Some(ObjectType::Pair(pair))) => {
+                6u8.hash(hasher);
+                if depth < MAX_HASH_DEPTH {
+                    self.hash_value(heap, pair.head, depth + 1, hasher);
+                    self.hash_value(heap, pair.tail, depth + 1, hasher);
+                }
+            }
+            _ => (4u8, handle).hash(hasher),
+        }
+    }
+
+    /// Compares two keys. Pairs that refer back to themselves are equal
+    /// when nothing reachable through them tells them apart.
+    pub(crate) fn equal(self, heap: &Heap, a: Value, b: Value) -> bool {
+        if self == MapMode::Identity {
+            return same(a, b);
+        }
+
+        let mut pending = vec![(a, b)];
+        let mut assumed = HashSet::new();
+        while let Some((a, b)) = pending.pop() {
+            let (x, y) = match (a, b) {
+                (Value::Object(x), Value::Object(y)) => (x, y),
+                _ if same(a, b) => continue,
+                _ => return false,
+            };
+            if x == y || !assumed.insert((x, y)) {
+                continue;
+            }
+
+            let obj_type = |handle| heap.get(handle).map(|object| &object.obj_type);
+            match (obj_type(x), obj_type(y)) {
+                (Some(ObjectType::String(s)), Some(ObjectType::String(t))) if s == t => {}
+                (Some(ObjectType::Pair(p)), Some(ObjectType::Pair(q))) => {
+                    pending.push((p.head, q.head));
+                    pending.push((p.tail, q.tail));
+                }
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Identity of two values. Floats compare by bits, so a NaN key can be
+/// found again.
+fn same(a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Float(x), Value::Float(y)) => x.to_bits() == y.to_bits(),
+        _ => a == b,
+    }
+}
+
+struct MapEntry {
+    hash: u64,
+    key: Value,
+    value: Value,
+}
+
+/// A hash table of values.
+///
+/// Hashing and comparing structural keys reads the heap, so the VM looks
+/// a key up first and then changes the entry it found by its slot. A pair
+/// changed after it was used as a structural key is no longer found under
+/// its new contents.
+pub struct Map {
+    mode: MapMode,
+    entries: Vec<MapEntry>,
+    /// Slots in `entries` by key hash.
+    slots: HashMap<u64, Vec<usize>>,
+}
+
+impl Map {
+    pub fn new(mode: MapMode) -> Self {
+        Map {
+            mode,
+            entries: Vec::new(),
+            slots: HashMap::new(),
+        }
+    }
+
+    pub fn mode(&self) -> MapMode {
+        self.mode
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Keys and values, in insertion order until something is removed.
+    pub fn iter(&self) -> impl Iterator<Item = (Value, Value)> + '_ {
+        self.entries.iter().map(|entry| (entry.key, entry.value))
+    }
+
+    /// Bytes taken by the entries and the slot index.
+    pub(crate) fn size(&self) -> usize {
+        self.entries.capacity() * std::mem::size_of::<MapEntry>()
+            + self.slots.capacity() * std::mem::size_of::<(u64, Vec<usize>)>()
+    }
+
+    /// The slot holding `key`, whose hash in this map's mode is `hash`.
+    pub(crate) fn find(&self, heap: &Heap, hash: u64, key: Value) -> Option<usize> {
+        self.slots
+            .get(&hash)?
+            .iter()
+            .copied()
+            .find(|&slot| self.mode.equal(heap, self.entries[slot].key, key))
+    }
+
+    pub(crate) fn value(&self, slot: usize) -> Value {
+        self.entries[slot].value
+    }
+
+    pub(crate) fn replace(&mut self, slot: usize, value: Value) -> Value {
+        std::mem::replace(&mut self.entries[slot].value, value)
+    }
+
+    /// Adds an entry for a key that [`find`](Map::find) did not find.
+    pub(crate) fn insert(&mut self, hash: u64, key: Value, value: Value) -> Result<(), VmError> {
+        self.entries
+            .try_reserve(1)
+            .map_err(|_| VmError::OutOfMemory)?;
+        self.slots
+            .try_reserve(1)
+            .map_err(|_| VmError::OutOfMemory)?;
+        self.slots.entry(hash).or_default().push(self.entries.len());
+        self.entries.push(MapEntry { hash, key, value });
+        Ok(())
+    }
+
+    /// Removes the entry in `slot`, moving the last entry into its place.
+    pub(crate) fn remove(&mut self, slot: usize) -> (Value, Value) {
+        let last = self.entries.len() - 1;
+        let removed = self.entries.swap_remove(slot);
+        self.unindex(removed.hash, slot);
+        if slot != last {
+            let moved = self.entries[slot].hash;
+            let slots = self.slots.get_mut(&moved).expect("entry is indexed");
+            let index = slots
+                .iter()
+                .position(|&s| s == last)
+                .expect("entry is indexed");
+            slots[index] = slot;
+        }
+        (removed.key, removed.value)
+    }
+
+    fn unindex(&mut self, hash: u64, slot: usize) {
+        let slots = self.slots.get_mut(&hash).expect("entry is indexed");
+        slots.retain(|&s| s != slot);
+        if slots.is_empty() {
+            self.slots.remove(&hash);
+        }
+    }
+
+    pub(crate) fn trace(&self, mut f: impl FnMut(Value)) {
+        for entry in &self.entries {
+            f(entry.key);
+            f(entry.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::{Handle, Object, Pair};
+
+    fn string(heap: &mut Heap, value: &str) -> Value {
+        let obj = heap.insert(Object::new(ObjectType::String(value.to_owned())));
+        obj.unwrap().into()
+    }
+
+    fn pair(heap: &mut Heap, head: Value, tail: Value) -> Handle {
+        let obj = heap.insert(Object::new(ObjectType::Pair(Pair { head, tail })));
+        obj.unwrap()
+    }
+
+    fn set_tail(heap: &mut Heap, obj: Handle, tail: Value) {
+        match &mut heap.get_mut(obj).unwrap().obj_type {
+            ObjectType::Pair(pair) => pair.tail = tail,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn structural_keys_compare_contents() {
+        let mut heap = Heap::new();
+        let (a1, a2, b) = (
+            string(&mut heap, "a"),
+            string(&mut heap, "a"),
+            string(&mut heap, "b"),
+        );
+        let p = pair(&mut heap, a1, Value::Int(1)).into();
+        let q = pair(&mut heap, a2, Value::Int(1)).into();
+        let r = pair(&mut heap, b, Value::Int(1)).into();
+
+        let structural = MapMode::Structural;
+        assert!(structural.equal(&heap, a1, a2));
+        assert_eq!(structural.hash(&heap, a1), structural.hash(&heap, a2));
+        assert!(structural.equal(&heap, p, q));
+        assert_eq!(structural.hash(&heap, p), structural.hash(&heap, q));
+        assert!(!structural.equal(&heap, p, r));
+        assert!(!structural.equal(&heap, a1, Value::Int(1)));
+
+        assert!(!MapMode::Identity.equal(&heap, a1, a2));
+        assert!(!MapMode::Identity.equal(&heap, p, q));
+        assert!(MapMode::Identity.equal(&heap, Value::Float(f64::NAN), Value::Float(f64::NAN)));
+    }
+
+    #[test]
+    fn cyclic_keys_compare_without_looping() {
+        let mut heap = Heap::new();
+        let p = pair(&mut heap, Value::Int(1), Value::Nil);
+        let q = pair(&mut heap, Value::Int(1), Value::Nil);
+        let r = pair(&mut heap, Value::Int(2), Value::Nil);
+        set_tail(&mut heap, p, p.into());
+        set_tail(&mut heap, q, q.into());
+        set_tail(&mut heap, r, r.into());
+
+        let structural = MapMode::Structural;
+        assert!(structural.equal(&heap, p.into(), q.into()));
+        assert_eq!(
+            structural.hash(&heap, p.into()),
+            structural.hash(&heap, q.into())
+        );
+        assert!(!structural.equal(&heap, p.into(), r.into()));
+    }
+
+    #[test]
+    fn removing_keeps_the_other_entries_findable() {
+        let heap = Heap::new();
+        let mut map = Map::new(MapMode::Identity);
+        for i in 0..4 {
+            let key = Value::Int(i);
+            map.insert(map.mode.hash(&heap, key), key, Value::Int(i * 10))
+                .unwrap();
+        }
+
+        let find = |map: &Map, i| {
+            let key = Value::Int(i);
+            map.find(&heap, map.mode.hash(&heap, key), key)
+        };
+        let slot = find(&map, 1).unwrap();
+        assert_eq!(map.remove(slot), (Value::Int(1), Value::Int(10)));
+
+        assert_eq!(map.len(), 3);
+        assert_eq!(find(&map, 1), None);
+        for i in [0, 2, 3] {
+            let slot = find(&map, i).unwrap();
+            assert_eq!(map.value(slot), Value::Int(i * 10));
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use crate::heap::{Handle, Heap, Object};
+use crate::stats::{GcReason, GcRecord};
+
+/// Hooks into the allocation and collection lifecycle of a
+/// [`VM`](crate::VM), registered with
+/// [`VM::add_observer`](crate::VM::add_observer).
+///
+/// Observers only ever get shared references to the heap, so they can look
+/// at it but not change it while a collection is under way. Every method
+/// does nothing by default.
+pub trait GcObserver {
+    /// An object was allocated.
+    fn on_allocate(&mut self, _handle: Handle, _object: &Object) {}
+
+    /// A collection is about to scan the roots. Collections a collector
+    /// schedules itself, such as minor collections or the pause that ends
+    /// an incremental or concurrent cycle, are announced the same way.
+    fn on_gc_start(&mut self, _reason: GcReason, _heap: &Heap) {}
+
+    /// The collector is done with the roots. For collectors that mark
+    /// everything up front, the heap's mark bits are final at this point.
+    fn on_mark_end(&mut self, _heap: &Heap) {}
+
+    /// An object was freed. `handle` no longer resolves by now. Collectors
+    /// that free objects outside of collections, such as reference counting
+    /// or lazy sweeping, report them after the operation that freed them.
+    fn on_free(&mut self, _handle: Handle, _object: &Object) {}
+
+    /// A collection finished.
+    fn on_gc_end(&mut self, _record: &GcRecord, _heap: &Heap) {}
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+use std::time::Duration;
+
+/// Running totals over the lifetime of a [`VM`](crate::VM).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub objects_allocated: usize,
+    pub bytes_allocated: usize,
+    pub objects_freed: usize,
+    pub peak_objects: usize,
+    pub peak_bytes: usize,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+}
+
+impl GcStats {
+    pub(crate) fn record(&mut self, record: &GcRecord) {
+        self.collections += 1;
+        self.total_pause += record.duration;
+        self.max_pause = self.max_pause.max(record.duration);
+    }
+}
+
+/// What made the VM collect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GcReason {
+    /// [`VM::gc`](crate::VM::gc) was called.
+    Explicit,
+    /// Live objects reached the collection threshold.
+    Threshold,
+    /// Bytes on the heap reached the byte threshold.
+    ByteThreshold,
+    /// Live objects reached the heap limit.
+    HeapLimit,
+    /// The nursery filled up, or a minor collection was asked for.
+    Minor,
+    /// The collector finished a cycle it paces itself, such as an
+    /// incremental or concurrent marking cycle, during an allocation or a
+    /// step the host asked for.
+    Scheduled,
+}
+
+impl fmt::Display for GcReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GcReason::Explicit => write!(f, "explicit"),
+            GcReason::Threshold => write!(f, "threshold"),
+            GcReason::ByteThreshold => write!(f, "byte threshold"),
+            GcReason::HeapLimit => write!(f, "heap limit"),
+            GcReason::Minor => write!(f, "minor"),
+            GcReason::Scheduled => write!(f, "scheduled"),
+        }
+    }
+}
+
+/// One collection, as kept in the VM's history.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GcRecord {
+    pub reason: GcReason,
+    pub objects_before: usize,
+    pub objects_after: usize,
+    pub bytes_before: usize,
+    pub bytes_after: usize,
+    pub duration: Duration,
+}
+
+impl fmt::Display for GcRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "gc ({}): collected {} objects, {} remaining, in {:?}",
+            self.reason,
+            self.objects_before.saturating_sub(self.objects_after),
+            self.objects_after,
+            self.duration
+        )
+    }
+}
@@ -0,0 +1,40 @@
+use crate::heap::Handle;
+
+/// What the VM stack and object fields hold.
+///
+/// Nil, booleans, integers and floats are stored inline and never touch the
+/// heap; only `Object` refers to something a collector has to trace.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Object(Handle),
+}
+
+impl Value {
+    /// The handle of a heap object, or `None` for an immediate value.
+    pub fn as_object(&self) -> Option<Handle> {
+        match *self {
+            Value::Object(handle) => Some(handle),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Object(_) => "object",
+        }
+    }
+}
+
+impl From<Handle> for Value {
+    fn from(handle: Handle) -> Self {
+        Value::Object(handle)
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::heap::{Handle, Heap};
+use crate::value::Value;
+
+/// A broken heap invariant, as reported by
+/// [`VM::verify_heap`](crate::VM::verify_heap).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The heap's object count disagrees with the length of its object
+    /// list.
+    LengthMismatch { counted: usize, listed: usize },
+    /// The object list reaches the same object a second time.
+    ListedTwice(Handle),
+    /// The object list links from `from`, or from its head if `None`, to
+    /// `to`, which is either not on the heap or does not link back.
+    BrokenLink { from: Option<Handle>, to: Handle },
+    /// An object is still marked although no collection is under way.
+    Marked(Handle),
+    /// A root on the VM stack refers to an object that is not on the heap.
+    DanglingRoot(Handle),
+    /// A field of `from` refers to an object that is not on the heap.
+    Dangling { from: Handle, to: Handle },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::LengthMismatch { counted, listed } => write!(
+                f,
+                "heap counts {counted} objects but its object list holds {listed}"
+            ),
+            Violation::ListedTwice(handle) => write!(f, "{handle:?} is listed twice"),
+            Violation::BrokenLink {
+                from: Some(from),
+                to,
+            } => write!(f, "broken list link from {from:?} to {to:?}"),
+            Violation::BrokenLink { from: None, to } => {
+                write!(f, "broken list link from the head to {to:?}")
+            }
+            Violation::Marked(handle) => write!(f, "{handle:?} is still marked"),
+            Violation::DanglingRoot(handle) => write!(f, "root {handle:?} is not on the heap"),
+            Violation::Dangling { from, to } => {
+                write!(f, "{from:?} refers to {to:?}, which is not on the heap")
+            }
+        }
+    }
+}
+
+/// Checks the heap against the VM's roots. With `idle`, the collector has
+/// no work in progress or left over, so every object has to be unmarked
+/// and refer only to objects on the heap; otherwise garbage may still be
+/// waiting to be released, and only objects reachable from the roots are
+/// checked for dangling references.
+pub(crate) fn verify(heap: &Heap, roots: &[Value], idle: bool) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    let mut listed = HashSet::new();
+    let mut prev = None;
+    let mut next = heap.first_object();
+    while let Some(handle) = next {
+        let Some(object) = heap.get(handle) else {
+            violations.push(Violation::BrokenLink {
+                from: prev,
+                to: handle,
+            });
+            break;
+        };
+        if !listed.insert(handle) {
+            violations.push(Violation::ListedTwice(handle));
+            break;
+        }
+        if object.prev != prev {
+            violations.push(Violation::BrokenLink {
+                from: prev,
+                to: handle,
+            });
+        }
+
+        if idle {
+            if heap.is_marked(handle) {
+                violations.push(Violation::Marked(handle));
+            }
+            object.trace(|child| {
+                if !heap.contains(child) {
+                    violations.push(Violation::Dangling {
+                        from: handle,
+                        to: child,
+                    });
+                }
+            });
+        }
+
+        prev = Some(handle);
+        next = object.next;
+    }
+
+    if listed.len() != heap.len() {
+        violations.push(Violation::LengthMismatch {
+            counted: heap.len(),
+            listed: listed.len(),
+        });
+    }
+
+    // Large objects hold no references, so only their marks can be off.
+    if idle {
+        for (handle, _) in heap.large_objects() {
+            if heap.is_marked(handle) {
+                violations.push(Violation::Marked(handle));
+            }
+        }
+    }
+
+    let mut reached = HashSet::new();
+    let mut gray = Vec::new();
+    for root in roots.iter().filter_map(Value::as_object) {
+        if !heap.contains(root) {
+            violations.push(Violation::DanglingRoot(root));
+        } else if reached.insert(root) {
+            gray.push(root);
+        }
+    }
+
+    if !idle {
+        while let Some(handle) = gray.pop() {
+            heap.get(handle).unwrap().trace(|child| {
+                if !heap.contains(child) {
+                    violations.push(Violation::Dangling {
+                        from: handle,
+                        to: child,
+                    });
+                } else if reached.insert(child) {
+                    gray.push(child);
+                }
+            });
+        }
+    }
+
+    violations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::heap::{Object, ObjectType, Pair};
+
+    fn string(heap: &mut Heap, value: &str) -> Handle {
+        heap.insert(Object::new(ObjectType::String(value.to_owned())))
+            .unwrap()
+    }
+
+    fn pair(heap: &mut Heap, head: Handle, tail: Handle) -> Handle {
+        let pair = Pair {
+            head: head.into(),
+            tail: tail.into(),
+        };
+        heap.insert(Object::new(ObjectType::Pair(pair))).unwrap()
+    }
+
+    #[test]
+    fn a_consistent_heap_passes() {
+        let mut heap = Heap::new();
+
+        let a = string(&mut heap, "1");
+        let b = string(&mut heap, "2");
+        let p = pair(&mut heap, a, b);
+
+        assert_eq!(verify(&heap, &[p.into()], true), vec![]);
+    }
+
+    #[test]
+    fn dangling_references_are_reported() {
+        let mut heap = Heap::new();
+
+        let a = string(&mut heap, "1");
+        let b = string(&mut heap, "2");
+        let p = pair(&mut heap, a, b);
+        heap.remove(b);
+
+        let dangling = Violation::Dangling { from: p, to: b };
+        assert_eq!(verify(&heap, &[p.into()], true), vec![dangling.clone()]);
+        assert_eq!(verify(&heap, &[p.into()], false), vec![dangling]);
+        assert_eq!(verify(&heap, &[a.into()], false), vec![]);
+        assert_eq!(
+            verify(&heap, &[b.into()], false),
+            vec![Violation::DanglingRoot(b)]
+        );
+    }
+
+    #[test]
+    fn leftover_marks_are_reported_while_idle() {
+        let mut heap = Heap::new();
+
+        let a = string(&mut heap, "1");
+        heap.mark(a).unwrap();
+
+        assert_eq!(verify(&heap, &[a.into()], true), vec![Violation::Marked(a)]);
+        assert_eq!(verify(&heap, &[a.into()], false), vec![]);
+    }
+
+    #[test]
+    fn broken_object_lists_are_reported() {
+        let mut heap = Heap::new();
+
+        let a = string(&mut heap, "1");
+        let b = string(&mut heap, "2");
+        heap.get_mut(a).unwrap().next = Some(b);
+        assert_eq!(verify(&heap, &[], true), vec![Violation::ListedTwice(b)]);
+
+        heap.get_mut(a).unwrap().next = None;
+        heap.get_mut(a).unwrap().prev = None;
+        assert_eq!(
+            verify(&heap, &[], true),
+            vec![Violation::BrokenLink {
+                from: Some(b),
+                to: a
+            }]
+        );
+
+        heap.get_mut(b).unwrap().next = None;
+        assert_eq!(
+            verify(&heap, &[], true),
+            vec![Violation::LengthMismatch {
+                counted: 2,
+                listed: 1
+            }]
+        );
+    }
+}
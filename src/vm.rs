@@ -0,0 +1,1511 @@
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::time::Instant;
+
+use crate::collector::{Collector, Concurrent, Generational, MarkSweep, Stepping};
+use crate::config::GcConfig;
+use crate::error::VmError;
+use crate::heap::{Handle, Heap, Object, ObjectType, Pair};
+use crate::map::{Map, MapMode};
+use crate::observer::GcObserver;
+use crate::stats::{GcReason, GcRecord, GcStats};
+use crate::value::Value;
+use crate::verify::{self, Violation};
+
+pub struct VM<C: Collector = MarkSweep> {
+    stack: Vec<Value>,
+    config: GcConfig,
+    heap: Heap,
+    collector: C,
+    max_objects: usize,
+    max_bytes: usize,
+    stats: GcStats,
+    history: VecDeque<GcRecord>,
+    observers: Vec<Box<dyn GcObserver + Send>>,
+    /// Weak: entries whose string was collected are dropped after the
+    /// collection that found it.
+    strings: HashMap<String, Handle>,
+}
+
+impl VM {
+    pub fn new(max_size: usize) -> Self {
+        VM::with_config(GcConfig::default().stack_size(max_size))
+    }
+
+    pub fn with_config(config: GcConfig) -> Self {
+        VM::with_collector(config, MarkSweep::new())
+    }
+}
+
+impl<C: Collector> VM<C> {
+    pub fn with_collector(config: GcConfig, collector: C) -> Self {
+        VM {
+            stack: Vec::with_capacity(config.stack_size),
+            max_objects: config.initial_threshold,
+            max_bytes: config.byte_threshold,
+            heap: Heap::with_large_object_size(config.large_object_size),
+            config,
+            collector,
+            stats: GcStats::default(),
+            history: VecDeque::new(),
+            observers: Vec::new(),
+            strings: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &GcConfig {
+        &self.config
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn collector(&self) -> &C {
+        &self.collector
+    }
+
+    pub fn stats(&self) -> GcStats {
+        GcStats {
+            objects_freed: self.stats.objects_allocated - self.heap_objects(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// The most recent collections, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = &GcRecord> + '_ {
+        self.history.iter()
+    }
+
+    /// Registers an observer, to be called after the ones added before it.
+    pub fn add_observer(&mut self, observer: impl GcObserver + Send + 'static) {
+        self.heap.keep_freed();
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Checks the heap's invariants against the current roots, returning
+    /// every violation found.
+    pub fn verify_heap(&self) -> Vec<Violation> {
+        verify::verify(&self.heap, &self.stack, self.collector.is_idle())
+    }
+
+    pub fn set_pair_tail(
+        &mut self,
+        obj: Handle,
+        new_tail: impl Into<Value>,
+    ) -> Result<(), VmError> {
+        let new_tail = new_tail.into();
+        self.check_value(new_tail)?;
+
+        let old_tail = match &mut self
+            .heap
+            .get_mut(obj)
+            .ok_or(VmError::InvalidHandle)?
+            .obj_type
+        {
+            ObjectType::Pair(pair) => std::mem::replace(&mut pair.tail, new_tail),
+            other => {
+                return Err(VmError::TypeMismatch {
+                    expected: "pair",
+                    found: other.name(),
+                })
+            }
+        };
+
+        self.collector
+            .write_barrier(&mut self.heap, obj, old_tail, new_tail);
+        self.report_freed();
+        Ok(())
+    }
+
+    /// Pushes a value, which for an object has to be on the heap. Nothing
+    /// is allocated.
+    pub fn push(&mut self, value: impl Into<Value>) -> Result<(), VmError> {
+        let value = value.into();
+        self.check_value(value)?;
+        if self.stack.len() >= self.config.stack_size {
+            return Err(VmError::StackOverflow);
+        }
+
+        self.stack.push(value);
+        if let Value::Object(obj) = value {
+            self.collector.root_pushed(&mut self.heap, obj);
+        }
+        Ok(())
+    }
+
+    pub fn push_int(&mut self, value: i64) -> Result<(), VmError> {
+        self.push(Value::Int(value))
+    }
+
+    pub fn push_pair(&mut self) -> Result<Handle, VmError> {
+        let [head, tail] = match self.stack[..] {
+            [.., head, tail] => [head, tail],
+            _ => return Err(VmError::StackUnderflow),
+        };
+
+        // The operands stay on the stack until the pair exists, so a
+        // collection triggered by the allocation keeps them alive.
+        let obj = self.new_object(ObjectType::Pair(Pair { head, tail }))?;
+        self.pop()?;
+        self.pop()?;
+        self.push(obj)?;
+        Ok(obj)
+    }
+
+    pub fn push_str(&mut self, value: &str) -> Result<Handle, VmError> {
+        if self.stack.len() >= self.config.stack_size {
+            return Err(VmError::StackOverflow);
+        }
+
+        let obj = self.new_string(value.to_owned())?;
+        self.push(obj)?;
+        Ok(obj)
+    }
+
+    /// Replaces the two strings on top of the stack with their
+    /// concatenation.
+    pub fn concat(&mut self) -> Result<Handle, VmError> {
+        let [head, tail] = match self.stack[..] {
+            [.., head, tail] => [head, tail],
+            _ => return Err(VmError::StackUnderflow),
+        };
+
+        let value = [self.get_str(head)?, self.get_str(tail)?].concat();
+        let obj = self.new_string(value)?;
+        self.pop()?;
+        self.pop()?;
+        self.push(obj)?;
+        Ok(obj)
+    }
+
+    /// Replaces the string on top of the stack with the bytes in `range`,
+    /// which has to start and end on character boundaries.
+    pub fn slice(&mut self, range: Range<usize>) -> Result<Handle, VmError> {
+        let &top = self.stack.last().ok_or(VmError::StackUnderflow)?;
+
+        let value = self
+            .get_str(top)?
+            .get(range)
+            .ok_or(VmError::IndexOutOfBounds)?
+            .to_owned();
+        let obj = self.new_string(value)?;
+        self.pop()?;
+        self.push(obj)?;
+        Ok(obj)
+    }
+
+    pub fn get_str(&self, value: impl Into<Value>) -> Result<&str, VmError> {
+        let obj = match value.into() {
+            Value::Object(obj) => obj,
+            other => {
+                return Err(VmError::TypeMismatch {
+                    expected: "string",
+                    found: other.name(),
+                })
+            }
+        };
+
+        match &self.heap.get(obj).ok_or(VmError::InvalidHandle)?.obj_type {
+            ObjectType::String(value) => Ok(value),
+            other => Err(VmError::TypeMismatch {
+                expected: "string",
+                found: other.name(),
+            }),
+        }
+    }
+
+    /// Pushes a byte buffer holding a copy of `value`. Buffers of at least
+    /// [`GcConfig::large_object_size`] bytes go to the large-object space.
+    pub fn push_bytes(&mut self, value: &[u8]) -> Result<Handle, VmError> {
+        if self.stack.len() >= self.config.stack_size {
+            return Err(VmError::StackOverflow);
+        }
+
+        let obj = self.new_object(ObjectType::Bytes(value.to_vec()))?;
+        self.push(obj)?;
+        Ok(obj)
+    }
+
+    pub fn get_bytes(&self, value: impl Into<Value>) -> Result<&[u8], VmError> {
+        let obj = match value.into() {
+            Value::Object(obj) => obj,
+            other => {
+                return Err(VmError::TypeMismatch {
+                    expected: "bytes",
+                    found: other.name(),
+                })
+            }
+        };
+
+        match &self.heap.get(obj).ok_or(VmError::InvalidHandle)?.obj_type {
+            ObjectType::Bytes(value) => Ok(value),
+            other => Err(VmError::TypeMismatch {
+                expected: "bytes",
+                found: other.name(),
+            }),
+        }
+    }
+
+    /// Pushes an empty vector with room for `capacity` elements, all of
+    /// which count towards the heap's size right away.
+    pub fn push_vector(&mut self, capacity: usize) -> Result<Handle, VmError> {
+        if self.stack.len() >= self.config.stack_size {
+            return Err(VmError::StackOverflow);
+        }
+
+        let mut elements = Vec::new();
+        elements
+            .try_reserve_exact(capacity)
+            .map_err(|_| VmError::OutOfMemory)?;
+        let obj = self.new_object(ObjectType::Vector(elements))?;
+        self.push(obj)?;
+        Ok(obj)
+    }
+
+    pub fn vector_push(&mut self, vector: Handle, value: impl Into<Value>) -> Result<(), VmError> {
+        let value = value.into();
+        self.check_value(value)?;
+
+        self.modify_vector(vector, |elements| {
+            elements.try_reserve(1).map_err(|_| VmError::OutOfMemory)?;
+            elements.push(value);
+            Ok(())
+        })?;
+        self.collector
+            .write_barrier(&mut self.heap, vector, Value::Nil, value);
+        self.report_freed();
+        Ok(())
+    }
+
+    pub fn vector_get(&self, vector: Handle, index: usize) -> Result<Value, VmError> {
+        self.vector(vector)?
+            .get(index)
+            .copied()
+            .ok_or(VmError::IndexOutOfBounds)
+    }
+
+    pub fn vector_set(
+        &mut self,
+        vector: Handle,
+        index: usize,
+        value: impl Into<Value>,
+    ) -> Result<(), VmError> {
+        let value = value.into();
+        self.check_value(value)?;
+
+        let old = self.modify_vector(vector, |elements| {
+            let element = elements.get_mut(index).ok_or(VmError::IndexOutOfBounds)?;
+            Ok(std::mem::replace(element, value))
+        })?;
+        self.collector
+            .write_barrier(&mut self.heap, vector, old, value);
+        self.report_freed();
+        Ok(())
+    }
+
+    pub fn vector_len(&self, vector: Handle) -> Result<usize, VmError> {
+        Ok(self.vector(vector)?.len())
+    }
+
+    /// Pushes an empty map comparing its keys according to `mode`.
+    pub fn push_map(&mut self, mode: MapMode) -> Result<Handle, VmError> {
+        if self.stack.len() >= self.config.stack_size {
+            return Err(VmError::StackOverflow);
+        }
+
+        let obj = self.new_object(ObjectType::Map(Map::new(mode)))?;
+        self.push(obj)?;
+        Ok(obj)
+    }
+
+    /// Maps `key` to `value`, returning the value it replaced.
+    pub fn map_insert(
+        &mut self,
+        map: Handle,
+        key: impl Into<Value>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, VmError> {
+        let (key, value) = (key.into(), value.into());
+        self.check_value(key)?;
+        self.check_value(value)?;
+
+        let (hash, slot) = self.map_slot(map, key)?;
+        let old = self.modify_map(map, |entries| match slot {
+            Some(slot) => Ok(Some(entries.replace(slot, value))),
+            None => entries.insert(hash, key, value).map(|()| None),
+        })?;
+
+        match old {
+            Some(old) => {
+                self.collector
+                    .write_barrier(&mut self.heap, map, old, value);
+            }
+            None => {
+                self.collector
+                    .write_barrier(&mut self.heap, map, Value::Nil, key);
+                self.collector
+                    .write_barrier(&mut self.heap, map, Value::Nil, value);
+            }
+        }
+        self.report_freed();
+        Ok(old)
+    }
+
+    pub fn map_get(&self, map: Handle, key: impl Into<Value>) -> Result<Option<Value>, VmError> {
+        let key = key.into();
+        self.check_value(key)?;
+
+        let map = self.map(map)?;
+        let hash = map.mode().hash(&self.heap, key);
+        Ok(map.find(&self.heap, hash, key).map(|slot| map.value(slot)))
+    }
+
+    /// Removes `key` from the map, returning the value it was mapped to.
+    pub fn map_remove(
+        &mut self,
+        map: Handle,
+        key: impl Into<Value>,
+    ) -> Result<Option<Value>, VmError> {
+        let key = key.into();
+        self.check_value(key)?;
+
+        let Some(slot) = self.map_slot(map, key)?.1 else {
+            return Ok(None);
+        };
+        let (old_key, old_value) = self.modify_map(map, |entries| Ok(entries.remove(slot)))?;
+        self.collector
+            .write_barrier(&mut self.heap, map, old_key, Value::Nil);
+        self.collector
+            .write_barrier(&mut self.heap, map, old_value, Value::Nil);
+        self.report_freed();
+        Ok(Some(old_value))
+    }
+
+    pub fn map_len(&self, map: Handle) -> Result<usize, VmError> {
+        Ok(self.map(map)?.len())
+    }
+
+    /// The keys and values in the map.
+    pub fn map_entries(
+        &self,
+        map: Handle,
+    ) -> Result<impl Iterator<Item = (Value, Value)> + '_, VmError> {
+        Ok(self.map(map)?.iter())
+    }
+
+    pub fn pop(&mut self) -> Result<Value, VmError> {
+        let value = self.stack.pop().ok_or(VmError::StackUnderflow)?;
+        if let Value::Object(obj) = value {
+            self.collector.root_popped(&mut self.heap, obj);
+            self.report_freed();
+        }
+        Ok(value)
+    }
+
+    pub fn gc(&mut self) -> Result<(), VmError> {
+        self.collect_garbage(GcReason::Explicit)
+    }
+
+    /// Completes work the last collection left for later, such as sweeping
+    /// in lazy-sweep mode.
+    pub fn finish_gc(&mut self) -> Result<(), VmError> {
+        let result = self.collector.finish(&mut self.heap);
+        self.report_freed();
+        result
+    }
+
+    /// Runs a collection and records it. Every collection goes through
+    /// here, whether the host, the VM's thresholds or the collector's own
+    /// schedule asked for it.
+    fn collect_garbage(&mut self, reason: GcReason) -> Result<(), VmError> {
+        let scheduled = matches!(reason, GcReason::Minor | GcReason::Scheduled);
+        self.check_heap("before");
+        let objects_before = self.live_objects();
+        let bytes_before = self.heap.bytes();
+        for observer in &mut self.observers {
+            observer.on_gc_start(reason, &self.heap);
+        }
+        let start = Instant::now();
+
+        if scheduled {
+            self.collector.scan_scheduled(&mut self.heap, &self.stack)?;
+        } else {
+            self.collector.scan_roots(&mut self.heap, &self.stack)?;
+        }
+        for observer in &mut self.observers {
+            observer.on_mark_end(&self.heap);
+        }
+        if scheduled {
+            self.collector.collect_scheduled(&mut self.heap)?;
+        } else {
+            self.collector.collect(&mut self.heap)?;
+        }
+        self.check_heap("after");
+
+        let heap = &self.heap;
+        self.strings.retain(|_, &mut obj| heap.contains(obj));
+
+        let record = GcRecord {
+            reason,
+            objects_before,
+            objects_after: self.live_objects(),
+            bytes_before,
+            bytes_after: self.heap.bytes(),
+            duration: start.elapsed(),
+        };
+        // A minor collection leaves the old generation, which the thresholds
+        // are for, as it was.
+        if reason != GcReason::Minor {
+            self.max_objects = self.config.next_threshold(record.objects_after);
+            self.max_bytes = self.config.next_byte_threshold(record.bytes_after);
+        }
+
+        self.report_freed();
+        for observer in &mut self.observers {
+            observer.on_gc_end(&record, &self.heap);
+        }
+        if self.config.logging {
+            eprintln!("{record}");
+        }
+        self.stats.record(&record);
+        if self.config.history_len > 0 {
+            if self.history.len() == self.config.history_len {
+                self.history.pop_front();
+            }
+            self.history.push_back(record);
+        }
+        Ok(())
+    }
+
+    fn check_heap(&self, when: &str) {
+        if !self.config.verify_heap {
+            return;
+        }
+        let violations = self.verify_heap();
+        if !violations.is_empty() {
+            let violations: Vec<_> = violations.iter().map(ToString::to_string).collect();
+            panic!(
+                "heap verification failed {when} gc: {}",
+                violations.join("; ")
+            );
+        }
+    }
+
+    /// Tells the observers about the objects freed since the last report,
+    /// then drops them.
+    fn report_freed(&mut self) {
+        if self.observers.is_empty() {
+            return;
+        }
+        for (handle, object) in self.heap.take_freed() {
+            for observer in &mut self.observers {
+                observer.on_free(handle, &object);
+            }
+        }
+    }
+
+    /// Objects on the heap, large ones included.
+    fn heap_objects(&self) -> usize {
+        self.heap.len() + self.heap.large_len()
+    }
+
+    fn check_value(&self, value: Value) -> Result<(), VmError> {
+        match value {
+            Value::Object(obj) if !self.collector.is_live(&self.heap, obj) => {
+                Err(VmError::InvalidHandle)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn new_object(&mut self, obj_type: ObjectType) -> Result<Handle, VmError> {
+        let object = Object::new(obj_type);
+        let size = object.size();
+        let bytes = self
+            .heap
+            .bytes()
+            .checked_add(size)
+            .ok_or(VmError::OutOfMemory)?;
+
+        if self.heap_full() {
+            self.collect_garbage(GcReason::HeapLimit)?;
+        } else {
+            let due = if self.collector.threshold_objects(&self.heap) >= self.max_objects {
+                Some(GcReason::Threshold)
+            } else {
+                (bytes > self.max_bytes).then_some(GcReason::ByteThreshold)
+            };
+            if let Some(reason) = self.collector.schedule(
+                &mut self.heap,
+                &self.stack,
+                due,
+                self.config.step_budget,
+            )? {
+                self.collect_garbage(reason)?;
+            }
+        }
+
+        if self.heap_full() {
+            return Err(VmError::OutOfMemory);
+        }
+
+        let handle = self.collector.allocate(&mut self.heap, object)?;
+
+        self.report_freed();
+        let object = self.heap.get(handle).expect("just allocated");
+        for observer in &mut self.observers {
+            observer.on_allocate(handle, object);
+        }
+
+        self.stats.objects_allocated += 1;
+        self.stats.bytes_allocated += size;
+        self.stats.peak_objects = self.stats.peak_objects.max(self.heap_objects());
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.heap.bytes());
+        Ok(handle)
+    }
+
+    /// Runs `f` on an object, counting any buffer it grows as allocated.
+    fn modify_object<R>(
+        &mut self,
+        obj: Handle,
+        f: impl FnOnce(&mut ObjectType) -> Result<R, VmError>,
+    ) -> Result<R, VmError> {
+        let before = self.heap.bytes();
+        let result = self
+            .heap
+            .modify(obj, |object| f(&mut object.obj_type))
+            .ok_or(VmError::InvalidHandle)?;
+
+        let grown = self.heap.bytes().saturating_sub(before);
+        self.stats.bytes_allocated += grown;
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.heap.bytes());
+        result
+    }
+
+    fn modify_vector<R>(
+        &mut self,
+        vector: Handle,
+        f: impl FnOnce(&mut Vec<Value>) -> Result<R, VmError>,
+    ) -> Result<R, VmError> {
+        self.modify_object(vector, |obj_type| match obj_type {
+            ObjectType::Vector(elements) => f(elements),
+            other => Err(VmError::TypeMismatch {
+                expected: "vector",
+                found: other.name(),
+            }),
+        })
+    }
+
+    fn modify_map<R>(
+        &mut self,
+        map: Handle,
+        f: impl FnOnce(&mut Map) -> Result<R, VmError>,
+    ) -> Result<R, VmError> {
+        self.modify_object(map, |obj_type| match obj_type {
+            ObjectType::Map(map) => f(map),
+            other => Err(VmError::TypeMismatch {
+                expected: "map",
+                found: other.name(),
+            }),
+        })
+    }
+
+    fn vector(&self, vector: Handle) -> Result<&[Value], VmError> {
+        match &self
+            .heap
+            .get(vector)
+            .ok_or(VmError::InvalidHandle)?
+            .obj_type
+        {
+            ObjectType::Vector(elements) => Ok(elements),
+            other => Err(VmError::TypeMismatch {
+                expected: "vector",
+                found: other.name(),
+            }),
+        }
+    }
+
+    fn map(&self, map: Handle) -> Result<&Map, VmError> {
+        match &self.heap.get(map).ok_or(VmError::InvalidHandle)?.obj_type {
+            ObjectType::Map(map) => Ok(map),
+            other => Err(VmError::TypeMismatch {
+                expected: "map",
+                found: other.name(),
+            }),
+        }
+    }
+
+    /// The hash of `key` in the map's mode, and the slot holding it if the
+    /// map has it.
+    fn map_slot(&self, map: Handle, key: Value) -> Result<(u64, Option<usize>), VmError> {
+        let map = self.map(map)?;
+        let hash = map.mode().hash(&self.heap, key);
+        Ok((hash, map.find(&self.heap, hash, key)))
+    }
+
+    fn new_string(&mut self, value: String) -> Result<Handle, VmError> {
+        if !self.config.intern_strings {
+            return self.new_object(ObjectType::String(value));
+        }
+
+        // While a collection is under way, the interned string may be
+        // garbage the collector already gave up on, and handing it out
+        // again would resurrect it. A fresh copy takes over its entry.
+        if self.collector.is_idle() {
+            if let Some(&obj) = self.strings.get(&value) {
+                if self.heap.contains(obj) {
+                    return Ok(obj);
+                }
+            }
+        }
+
+        let obj = self.new_object(ObjectType::String(value.clone()))?;
+        self.strings.insert(value, obj);
+        Ok(obj)
+    }
+
+    fn heap_full(&self) -> bool {
+        self.config
+            .max_heap_objects
+            .is_some_and(|limit| self.live_objects() >= limit)
+    }
+
+    fn live_objects(&self) -> usize {
+        self.collector.live_objects(&self.heap)
+    }
+}
+
+impl VM<Generational> {
+    /// Collects the nursery only, promoting survivors that are old enough.
+    pub fn minor_gc(&mut self) -> Result<(), VmError> {
+        self.collect_garbage(GcReason::Minor)
+    }
+
+    /// Collects both generations and tenures every survivor.
+    pub fn major_gc(&mut self) -> Result<(), VmError> {
+        self.gc()
+    }
+}
+
+impl<C: Stepping> VM<C> {
+    /// Advances the current marking cycle, starting one if none is running,
+    /// by scanning at most `budget` gray objects, and collects once nothing
+    /// is left to mark. Returns how many objects were scanned.
+    pub fn gc_step(&mut self, budget: usize) -> Result<usize, VmError> {
+        let scanned = self.collector.step(&mut self.heap, &self.stack, budget)?;
+        if self.collector.marking_done() {
+            self.collect_garbage(GcReason::Scheduled)?;
+        }
+        Ok(scanned)
+    }
+}
+
+impl VM<Concurrent> {
+    /// Snapshots the roots and starts marking on a background thread,
+    /// unless a cycle is already running.
+    pub fn start_marking(&mut self) {
+        self.collector.start_marking(&self.stack);
+    }
+
+    /// Waits for the running cycle's marker to finish, then sweeps.
+    pub fn finish_marking(&mut self) -> Result<(), VmError> {
+        if !self.collector.is_marking() {
+            return Ok(());
+        }
+        self.collect_garbage(GcReason::Scheduled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::collector::{Incremental, RefCount};
+    use crate::heap::dropped_objects;
+
+    /// Writes every event it sees to a shared log.
+    struct Recorder {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Recorder {
+        fn new(name: &'static str, log: &Arc<Mutex<Vec<String>>>) -> Self {
+            Recorder {
+                name,
+                log: Arc::clone(log),
+            }
+        }
+
+        fn record(&self, event: String) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("{}: {event}", self.name));
+        }
+    }
+
+    impl GcObserver for Recorder {
+        fn on_allocate(&mut self, _handle: Handle, object: &Object) {
+            self.record(format!("allocate {}", object.obj_type.name()));
+        }
+
+        fn on_gc_start(&mut self, reason: GcReason, heap: &Heap) {
+            self.record(format!("start {reason} with {}", heap.len()));
+        }
+
+        fn on_mark_end(&mut self, heap: &Heap) {
+            let marked = heap.iter().filter(|&(h, _)| heap.is_marked(h)).count();
+            self.record(format!("marked {marked}"));
+        }
+
+        fn on_free(&mut self, _handle: Handle, object: &Object) {
+            self.record(format!("free {}", object.obj_type.name()));
+        }
+
+        fn on_gc_end(&mut self, record: &GcRecord, heap: &Heap) {
+            assert_eq!(record.objects_after, heap.len());
+            self.record(format!("end with {}", record.objects_after));
+        }
+    }
+
+    fn take(log: &Arc<Mutex<Vec<String>>>) -> Vec<String> {
+        std::mem::take(&mut *log.lock().unwrap())
+    }
+
+    #[test]
+    fn sweep_unlinks_dead_objects() {
+        let mut vm = VM::new(10);
+
+        let one = vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        let three = vm.push_str("3").unwrap();
+        vm.pop().unwrap();
+
+        vm.push_str("4").unwrap();
+        let five = vm.push_str("5").unwrap();
+        vm.pop().unwrap();
+        vm.pop().unwrap();
+
+        let before = dropped_objects();
+        vm.gc().unwrap();
+
+        let mut live = Vec::new();
+        let mut obj = vm.heap().first_object();
+        while let Some(handle) = obj {
+            live.push(handle);
+            obj = vm.heap().get(handle).unwrap().next;
+        }
+
+        assert_eq!(dropped_objects() - before, 3);
+        assert_eq!(live.len(), vm.heap().len());
+        assert_eq!(live.len(), 2);
+        assert!(live.contains(&one));
+        assert!(!live.contains(&three));
+        assert!(!live.contains(&five));
+    }
+
+    #[test]
+    fn handles_are_copy() {
+        let mut vm = VM::new(10);
+
+        let one = vm.push_str("1").unwrap();
+        let two = vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+
+        match &vm.heap().get(pair).unwrap().obj_type {
+            ObjectType::Pair(p) => {
+                assert_eq!(p.head, one.into());
+                assert_eq!(p.tail, two.into());
+            }
+            _ => panic!("should be a pair"),
+        }
+    }
+
+    #[test]
+    fn stack_errors_are_reported() {
+        let mut vm = VM::new(2);
+
+        assert_eq!(vm.pop(), Err(VmError::StackUnderflow));
+
+        vm.push_str("1").unwrap();
+        assert_eq!(vm.push_pair(), Err(VmError::StackUnderflow));
+
+        vm.push_str("2").unwrap();
+        assert_eq!(vm.push_str("3"), Err(VmError::StackOverflow));
+
+        vm.push_pair().unwrap();
+        assert_eq!(vm.stack.len(), 1);
+    }
+
+    #[test]
+    fn set_pair_tail_checks_its_arguments() {
+        let mut vm = VM::new(10);
+
+        let one = vm.push_str("1").unwrap();
+        let two = vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+
+        assert_eq!(
+            vm.set_pair_tail(one, two),
+            Err(VmError::TypeMismatch {
+                expected: "pair",
+                found: "string",
+            })
+        );
+
+        let garbage = vm.push_str("3").unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+
+        assert_eq!(vm.set_pair_tail(pair, garbage), Err(VmError::InvalidHandle));
+        assert_eq!(vm.set_pair_tail(garbage, pair), Err(VmError::InvalidHandle));
+    }
+
+    #[test]
+    fn pair_operands_survive_collection_during_allocation() {
+        let mut vm = VM::with_config(GcConfig::new().initial_threshold(2));
+
+        let one = vm.push_str("1").unwrap();
+        let two = vm.push_str("2").unwrap();
+        vm.push_pair().unwrap();
+
+        assert!(vm.heap().contains(one));
+        assert!(vm.heap().contains(two));
+    }
+
+    #[test]
+    fn initial_threshold_delays_first_collection() {
+        let mut vm = VM::with_config(GcConfig::new().initial_threshold(3));
+
+        vm.push_str("1").unwrap();
+        vm.pop().unwrap();
+        vm.push_str("2").unwrap();
+        vm.pop().unwrap();
+        vm.push_str("3").unwrap();
+        vm.pop().unwrap();
+        assert_eq!(vm.heap().len(), 3);
+
+        vm.push_str("4").unwrap();
+        assert_eq!(vm.heap().len(), 1);
+    }
+
+    #[test]
+    fn growth_factor_scales_next_threshold() {
+        let mut vm = VM::with_config(GcConfig::new().growth_factor(3.0).min_threshold(1));
+
+        for i in 0..5 {
+            vm.push_str(&i.to_string()).unwrap();
+        }
+        vm.gc().unwrap();
+
+        assert_eq!(vm.max_objects, 15);
+    }
+
+    #[test]
+    fn min_threshold_applies_after_full_collection() {
+        let mut vm = VM::with_config(GcConfig::new().min_threshold(4));
+
+        vm.push_str("1").unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+
+        assert_eq!(vm.heap().len(), 0);
+        assert_eq!(vm.max_objects, 4);
+    }
+
+    #[test]
+    fn max_threshold_caps_growth() {
+        let mut vm = VM::with_config(GcConfig::new().max_threshold(10).stack_size(100));
+
+        for i in 0..20 {
+            vm.push_str(&i.to_string()).unwrap();
+        }
+        vm.gc().unwrap();
+
+        assert_eq!(vm.max_objects, 10);
+    }
+
+    #[test]
+    fn heap_limit_reports_out_of_memory() {
+        let mut vm = VM::with_config(GcConfig::new().max_heap_objects(2));
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        assert_eq!(vm.push_str("3"), Err(VmError::OutOfMemory));
+
+        vm.pop().unwrap();
+        vm.push_str("3").unwrap();
+        assert_eq!(vm.heap().len(), 2);
+    }
+
+    #[test]
+    fn stack_size_bounds_the_stack() {
+        let mut vm = VM::with_config(GcConfig::new().stack_size(1));
+
+        vm.push_str("1").unwrap();
+        assert_eq!(vm.push_str("2"), Err(VmError::StackOverflow));
+    }
+
+    #[test]
+    fn stats_count_allocations_and_collections() {
+        let mut vm = VM::new(10);
+        let size = Object::new(ObjectType::String("1".into())).size();
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        vm.push_str("3").unwrap();
+        vm.pop().unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+
+        let stats = vm.stats();
+        assert_eq!(stats.collections, 1);
+        assert_eq!(stats.objects_allocated, 3);
+        assert_eq!(stats.bytes_allocated, 3 * size);
+        assert_eq!(stats.objects_freed, 2);
+        assert_eq!(stats.peak_objects, 3);
+        assert_eq!(stats.peak_bytes, 3 * size);
+        assert_eq!(stats.total_pause, stats.max_pause);
+    }
+
+    #[test]
+    fn collections_record_what_triggered_them() {
+        let mut vm = VM::with_config(
+            GcConfig::new()
+                .initial_threshold(2)
+                .max_heap_objects(3)
+                .min_threshold(100),
+        );
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        vm.push_str("3").unwrap();
+        vm.push_str("4").unwrap_err();
+        vm.gc().unwrap();
+
+        let reasons: Vec<_> = vm.history().map(|record| record.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![GcReason::Threshold, GcReason::HeapLimit, GcReason::Explicit]
+        );
+
+        let record = vm.history().next().unwrap();
+        assert_eq!(record.objects_before, 2);
+        assert_eq!(record.objects_after, 2);
+        assert_eq!(
+            record.bytes_after,
+            2 * Object::new(ObjectType::String("1".into())).size()
+        );
+    }
+
+    #[test]
+    fn collector_driven_collections_are_recorded() {
+        let config = GcConfig::new().initial_threshold(1000);
+
+        let mut vm = VM::with_collector(config.clone(), Generational::new().nursery_size(4));
+        for i in 0..5 {
+            vm.push_str(&i.to_string()).unwrap();
+            vm.pop().unwrap();
+        }
+        vm.minor_gc().unwrap();
+        assert_eq!(
+            vm.history().map(|record| record.reason).collect::<Vec<_>>(),
+            vec![GcReason::Minor, GcReason::Minor]
+        );
+        assert_eq!(vm.stats().collections, 2);
+
+        let mut vm = VM::with_collector(config.clone(), Incremental::new());
+        vm.push_str("1").unwrap();
+        vm.pop().unwrap();
+        vm.gc_step(1).unwrap();
+        let record = vm.history().next().unwrap();
+        assert_eq!(record.reason, GcReason::Scheduled);
+        assert_eq!((record.objects_before, record.objects_after), (1, 0));
+
+        let mut vm = VM::with_collector(config, Concurrent::new());
+        vm.push_str("1").unwrap();
+        vm.start_marking();
+        vm.finish_marking().unwrap();
+        assert_eq!(
+            vm.history().map(|record| record.reason).collect::<Vec<_>>(),
+            vec![GcReason::Scheduled]
+        );
+        assert_eq!(vm.stats().collections, 1);
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut vm = VM::with_config(GcConfig::new().history_len(2));
+
+        vm.push_str("1").unwrap();
+        vm.gc().unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+        vm.gc().unwrap();
+
+        let after: Vec<_> = vm.history().map(|record| record.objects_after).collect();
+        assert_eq!(after, vec![0, 0]);
+        assert_eq!(vm.stats().collections, 3);
+    }
+
+    #[test]
+    fn observers_follow_a_collection() {
+        let mut vm = VM::new(10);
+        let log = Arc::default();
+        vm.add_observer(Recorder::new("a", &log));
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        vm.push_pair().unwrap();
+        vm.push_str("3").unwrap();
+        vm.pop().unwrap();
+
+        let before = dropped_objects();
+        vm.gc().unwrap();
+        assert_eq!(dropped_objects() - before, 1);
+
+        assert_eq!(
+            take(&log),
+            vec![
+                "a: allocate string",
+                "a: allocate string",
+                "a: allocate pair",
+                "a: allocate string",
+                "a: start explicit with 4",
+                "a: marked 3",
+                "a: free string",
+                "a: end with 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn observers_are_called_in_order() {
+        let mut vm = VM::new(10);
+        let log = Arc::default();
+        vm.add_observer(Recorder::new("a", &log));
+        vm.add_observer(Recorder::new("b", &log));
+
+        vm.push_str("1").unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+
+        let log = take(&log);
+        assert_eq!(log.len(), 10);
+        assert_eq!(&log[..2], ["a: allocate string", "b: allocate string"]);
+        assert_eq!(&log[6..8], ["a: free string", "b: free string"]);
+    }
+
+    #[test]
+    fn observers_follow_scheduled_collections() {
+        let config = GcConfig::new().initial_threshold(1000);
+        let log = Arc::default();
+
+        let mut vm = VM::with_collector(config.clone(), Generational::new().nursery_size(2));
+        vm.add_observer(Recorder::new("a", &log));
+        for i in 0..3 {
+            vm.push_str(&i.to_string()).unwrap();
+            vm.pop().unwrap();
+        }
+        assert_eq!(
+            take(&log)[2..],
+            [
+                "a: start minor with 2",
+                "a: marked 0",
+                "a: free string",
+                "a: free string",
+                "a: end with 0",
+                "a: allocate string",
+            ]
+        );
+
+        let mut vm = VM::with_collector(config, Incremental::new());
+        vm.add_observer(Recorder::new("a", &log));
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        vm.pop().unwrap();
+        take(&log);
+        vm.gc_step(1).unwrap();
+        assert_eq!(
+            take(&log),
+            vec![
+                "a: start scheduled with 2",
+                "a: marked 1",
+                "a: free string",
+                "a: end with 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn objects_freed_outside_collections_are_reported() {
+        let mut vm = VM::with_collector(GcConfig::new(), RefCount::new());
+        let log = Arc::default();
+        vm.add_observer(Recorder::new("a", &log));
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        vm.push_pair().unwrap();
+        take(&log);
+
+        vm.pop().unwrap();
+        let mut freed = take(&log);
+        freed.sort();
+        assert_eq!(
+            freed,
+            vec!["a: free pair", "a: free string", "a: free string"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "heap verification failed before gc")]
+    fn verification_catches_corruption_before_collecting() {
+        let mut vm = VM::with_config(GcConfig::new().verify_heap(true));
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+        let garbage = vm.push_str("3").unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+
+        // A pair field written behind the VM's back, as a missing barrier
+        // or root would leave it.
+        match &mut vm.heap.get_mut(pair).unwrap().obj_type {
+            ObjectType::Pair(p) => p.tail = garbage.into(),
+            _ => unreachable!(),
+        }
+        assert_eq!(
+            vm.verify_heap(),
+            vec![Violation::Dangling {
+                from: pair,
+                to: garbage
+            }]
+        );
+        vm.gc().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "heap verification failed before gc")]
+    fn verification_covers_scheduled_collections() {
+        let mut vm = VM::with_collector(
+            GcConfig::new().verify_heap(true).initial_threshold(1000),
+            Generational::new().nursery_size(4),
+        );
+
+        vm.push_str("1").unwrap();
+        vm.push_str("2").unwrap();
+        let pair = vm.push_pair().unwrap();
+        let garbage = vm.push_str("3").unwrap();
+        vm.pop().unwrap();
+        vm.major_gc().unwrap();
+
+        match &mut vm.heap.get_mut(pair).unwrap().obj_type {
+            ObjectType::Pair(p) => p.tail = garbage.into(),
+            _ => unreachable!(),
+        }
+        // Filling the nursery starts a minor collection.
+        for i in 0..5 {
+            vm.push_str(&i.to_string()).unwrap();
+            vm.pop().unwrap();
+        }
+    }
+
+    #[test]
+    fn strings_concatenate_and_slice() {
+        let mut vm = VM::new(10);
+
+        vm.push_str("garbage ").unwrap();
+        vm.push_str("collector").unwrap();
+        let both = vm.concat().unwrap();
+        assert_eq!(vm.get_str(both), Ok("garbage collector"));
+        assert_eq!(vm.stack, vec![both.into()]);
+
+        let part = vm.slice(8..17).unwrap();
+        assert_eq!(vm.get_str(part), Ok("collector"));
+        assert_eq!(vm.stack, vec![part.into()]);
+
+        vm.gc().unwrap();
+        assert_eq!(vm.heap().len(), 1);
+        assert_eq!(
+            vm.heap().bytes(),
+            std::mem::size_of::<Object>() + "collector".len()
+        );
+    }
+
+    #[test]
+    fn string_operations_check_their_operands() {
+        let mut vm = VM::new(10);
+
+        vm.push_str("é").unwrap();
+        assert_eq!(vm.slice(0..1), Err(VmError::IndexOutOfBounds));
+        assert_eq!(vm.slice(0..3), Err(VmError::IndexOutOfBounds));
+        assert_eq!(vm.concat(), Err(VmError::StackUnderflow));
+
+        vm.push_int(1).unwrap();
+        assert_eq!(
+            vm.concat(),
+            Err(VmError::TypeMismatch {
+                expected: "string",
+                found: "int",
+            })
+        );
+    }
+
+    #[test]
+    fn interned_strings_are_shared_while_reachable() {
+        let mut vm = VM::with_config(GcConfig::new().intern_strings(true));
+
+        let a = vm.push_str("ab").unwrap();
+        vm.push_str("a").unwrap();
+        vm.push_str("b").unwrap();
+        assert_eq!(vm.concat().unwrap(), a);
+        assert_eq!(vm.slice(0..2).unwrap(), a);
+        assert_eq!(vm.strings.len(), 3);
+
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+        assert_eq!(vm.strings.len(), 1);
+        assert_eq!(vm.push_str("ab").unwrap(), a);
+
+        vm.pop().unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+        assert!(vm.strings.is_empty());
+
+        let b = vm.push_str("ab").unwrap();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn interned_garbage_is_not_resurrected_by_a_pending_sweep() {
+        let config = GcConfig::new().intern_strings(true).verify_heap(true);
+        let mut vm = VM::with_collector(config, MarkSweep::new().lazy_sweep(1));
+
+        let garbage = vm.push_str("a").unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+        assert!(vm.collector().is_sweeping());
+
+        let fresh = vm.push_str("a").unwrap();
+        assert_ne!(fresh, garbage);
+        vm.finish_gc().unwrap();
+        assert!(!vm.heap().contains(garbage));
+        assert_eq!(vm.get_str(fresh), Ok("a"));
+    }
+
+    #[test]
+    fn immediates_do_not_allocate() {
+        let mut vm = VM::with_config(GcConfig::new().initial_threshold(2).stack_size(1000));
+
+        for i in 0..500 {
+            vm.push_int(i).unwrap();
+        }
+        vm.push(Value::Bool(true)).unwrap();
+        vm.push(Value::Float(0.5)).unwrap();
+        vm.push(Value::Nil).unwrap();
+
+        assert!(vm.heap().is_empty());
+        assert_eq!(vm.stats().collections, 0);
+        assert_eq!(vm.pop(), Ok(Value::Nil));
+        assert_eq!(vm.pop(), Ok(Value::Float(0.5)));
+    }
+
+    #[test]
+    fn pairs_hold_immediates_and_objects() {
+        let mut vm = VM::new(10);
+
+        vm.push_int(-1).unwrap();
+        let s = vm.push_str("s").unwrap();
+        let pair = vm.push_pair().unwrap();
+        vm.set_pair_tail(pair, Value::Bool(false)).unwrap();
+        vm.gc().unwrap();
+
+        assert!(!vm.heap().contains(s));
+        match &vm.heap().get(pair).unwrap().obj_type {
+            ObjectType::Pair(p) => {
+                assert_eq!(p.head, Value::Int(-1));
+                assert_eq!(p.tail, Value::Bool(false));
+            }
+            _ => panic!("should be a pair"),
+        }
+    }
+
+    #[test]
+    fn pushed_objects_have_to_be_on_the_heap() {
+        let mut vm = VM::new(10);
+
+        let garbage = vm.push_str("garbage").unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+
+        assert_eq!(vm.push(garbage), Err(VmError::InvalidHandle));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn vectors_check_their_operands() {
+        let mut vm = VM::new(10);
+
+        assert_eq!(vm.push_vector(usize::MAX), Err(VmError::OutOfMemory));
+        assert!(vm.heap().is_empty());
+
+        let vector = vm.push_vector(0).unwrap();
+        vm.vector_push(vector, Value::Int(7)).unwrap();
+        assert_eq!(vm.vector_len(vector), Ok(1));
+        assert_eq!(vm.vector_get(vector, 0), Ok(Value::Int(7)));
+        assert_eq!(vm.vector_get(vector, 1), Err(VmError::IndexOutOfBounds));
+        assert_eq!(
+            vm.vector_set(vector, 1, Value::Int(8)),
+            Err(VmError::IndexOutOfBounds)
+        );
+
+        let s = vm.push_str("s").unwrap();
+        assert_eq!(
+            vm.vector_push(s, Value::Nil),
+            Err(VmError::TypeMismatch {
+                expected: "vector",
+                found: "string",
+            })
+        );
+
+        let garbage = vm.push_str("garbage").unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+        assert_eq!(vm.vector_push(vector, garbage), Err(VmError::InvalidHandle));
+        assert_eq!(vm.vector_len(garbage), Err(VmError::InvalidHandle));
+    }
+
+    #[test]
+    fn vector_buffers_count_towards_the_heap_size() {
+        let element = std::mem::size_of::<Value>();
+        let mut vm = VM::with_config(
+            GcConfig::new()
+                .initial_threshold(1000)
+                .byte_threshold(100 * element),
+        );
+
+        let vector = vm.push_vector(10).unwrap();
+        let empty = std::mem::size_of::<Object>();
+        assert_eq!(vm.heap().bytes(), empty + 10 * element);
+
+        for i in 0..11 {
+            vm.vector_push(vector, Value::Int(i)).unwrap();
+        }
+        let grown = vm.heap().bytes();
+        assert!(grown > empty + 10 * element);
+        assert_eq!(vm.stats().bytes_allocated, grown);
+
+        vm.pop().unwrap();
+        vm.push_vector(100).unwrap();
+        let record = vm.history().next().unwrap();
+        assert_eq!(record.reason, GcReason::ByteThreshold);
+        assert_eq!(record.bytes_after, 0);
+    }
+
+    #[test]
+    fn maps_compare_keys_by_their_mode() {
+        let mut vm = VM::new(10);
+
+        let identity = vm.push_map(MapMode::Identity).unwrap();
+        let structural = vm.push_map(MapMode::Structural).unwrap();
+        let key = vm.push_str("key").unwrap();
+        assert_eq!(vm.map_insert(identity, key, Value::Int(1)), Ok(None));
+        assert_eq!(vm.map_insert(structural, key, Value::Int(1)), Ok(None));
+
+        let copy = vm.push_str("key").unwrap();
+        assert_eq!(vm.map_get(identity, key), Ok(Some(Value::Int(1))));
+        assert_eq!(vm.map_get(identity, copy), Ok(None));
+        assert_eq!(vm.map_get(structural, copy), Ok(Some(Value::Int(1))));
+        assert_eq!(
+            vm.map_insert(structural, copy, Value::Int(2)),
+            Ok(Some(Value::Int(1)))
+        );
+        assert_eq!(vm.stats().bytes_allocated, vm.heap().bytes());
+
+        let entries: Vec<_> = vm.map_entries(structural).unwrap().collect();
+        assert_eq!(entries, [(key.into(), Value::Int(2))]);
+        assert_eq!(vm.map_remove(structural, copy), Ok(Some(Value::Int(2))));
+        assert_eq!(vm.map_remove(structural, copy), Ok(None));
+        assert_eq!(vm.map_len(structural), Ok(0));
+        assert_eq!(vm.map_len(identity), Ok(1));
+    }
+
+    #[test]
+    fn maps_check_their_operands() {
+        let mut vm = VM::new(10);
+
+        let map = vm.push_map(MapMode::Identity).unwrap();
+        let s = vm.push_str("s").unwrap();
+        assert_eq!(
+            vm.map_insert(s, Value::Nil, Value::Nil),
+            Err(VmError::TypeMismatch {
+                expected: "map",
+                found: "string",
+            })
+        );
+
+        let garbage = vm.push_str("garbage").unwrap();
+        vm.pop().unwrap();
+        vm.gc().unwrap();
+        assert_eq!(
+            vm.map_insert(map, garbage, Value::Nil),
+            Err(VmError::InvalidHandle)
+        );
+        assert_eq!(
+            vm.map_insert(map, Value::Nil, garbage),
+            Err(VmError::InvalidHandle)
+        );
+        assert_eq!(vm.map_get(map, garbage), Err(VmError::InvalidHandle));
+        assert_eq!(vm.map_len(garbage), Err(VmError::InvalidHandle));
+    }
+
+    #[test]
+    fn large_buffers_count_towards_collection_by_size() {
+        let mut vm = VM::with_config(
+            GcConfig::new()
+                .initial_threshold(1000)
+                .large_object_size(1024)
+                .byte_threshold(3 * 1024),
+        );
+
+        for _ in 0..3 {
+            vm.push_bytes(&[0; 1024]).unwrap();
+            vm.pop().unwrap();
+        }
+        assert_eq!(vm.heap().len(), 0);
+        assert_eq!(vm.heap().large_len(), 1);
+
+        let record = vm.history().next().unwrap();
+        assert_eq!(record.reason, GcReason::ByteThreshold);
+        let stats = vm.stats();
+        assert_eq!(stats.collections, 1);
+        assert_eq!(stats.objects_freed, 2);
+        assert_eq!(stats.peak_objects, 2);
+    }
+
+    #[test]
+    fn bytes_check_their_operands() {
+        let mut vm = VM::new(10);
+
+        let bytes = vm.push_bytes(b"bytes").unwrap();
+        assert_eq!(vm.get_bytes(bytes), Ok(&b"bytes"[..]));
+        assert_eq!(
+            vm.get_str(bytes),
+            Err(VmError::TypeMismatch {
+                expected: "string",
+                found: "bytes",
+            })
+        );
+        assert_eq!(
+            vm.get_bytes(Value::Int(1)),
+            Err(VmError::TypeMismatch {
+                expected: "bytes",
+                found: "int",
+            })
+        );
+    }
+}